@@ -1,6 +1,6 @@
 use riscv::register::{
     sstatus::{self, Sstatus, SPP},
-    scause::{self, Trap, Exception},
+    scause::{self, Trap, Exception, Interrupt},
     stvec::{self, TrapMode}, stval,
 };
 const USER_STACK_SIZE: usize = 4096 * 2;
@@ -46,23 +46,50 @@ impl Runtime {
     pub fn resume(&mut self) -> ResumeResult {
         // note(unsafe): 当前上下文可以用的借用；如果超过借用的范围，生命周期会失效
         let user_ctx = unsafe { &mut *do_resume(&mut self.context as *mut _) };
-        let stval = stval::read();
-        match scause::read().cause() {
+        let (scause, stval) = (scause::read(), stval::read());
+        match scause.cause() {
+            Trap::Interrupt(Interrupt::SupervisorSoft) => ResumeResult::SoftwareInterrupt(user_ctx),
+            Trap::Interrupt(Interrupt::SupervisorTimer) => ResumeResult::TimerInterrupt(user_ctx),
+            Trap::Interrupt(Interrupt::SupervisorExternal) => ResumeResult::ExternalInterrupt(user_ctx),
             Trap::Exception(Exception::UserEnvCall) => ResumeResult::Syscall(user_ctx),
+            Trap::Exception(Exception::Breakpoint) => ResumeResult::Breakpoint(user_ctx),
+            Trap::Exception(Exception::InstructionMisaligned) => ResumeResult::InstructionMisaligned(user_ctx, stval),
+            Trap::Exception(Exception::InstructionFault) => ResumeResult::InstructionAccessFault(user_ctx, stval),
+            Trap::Exception(Exception::IllegalInstruction) => ResumeResult::IllegalInstruction(user_ctx, stval),
             Trap::Exception(Exception::LoadFault) => ResumeResult::LoadAccessFault(user_ctx, stval),
+            Trap::Exception(Exception::StoreMisaligned) => ResumeResult::StoreMisaligned(user_ctx, stval),
             Trap::Exception(Exception::StoreFault) => ResumeResult::StoreAccessFault(user_ctx, stval),
-            Trap::Exception(Exception::IllegalInstruction) => ResumeResult::IllegalInstruction(user_ctx),
-            _ => panic!("todo: handle more exceptions!")
+            Trap::Exception(Exception::InstructionPageFault) => ResumeResult::InstructionPageFault(user_ctx, stval),
+            Trap::Exception(Exception::LoadPageFault) => ResumeResult::LoadPageFault(user_ctx, stval),
+            Trap::Exception(Exception::StorePageFault) => ResumeResult::StorePageFault(user_ctx, stval),
+            // riscv库的Exception不一定包含读取未对齐异常，按异常编号判断
+            _ if scause.is_exception() && scause.code() == EXCEPTION_LOAD_MISALIGNED => ResumeResult::LoadMisaligned(user_ctx, stval),
+            _ => ResumeResult::Unknown(user_ctx, scause.bits(), stval),
         }
     }
 }
 
+const EXCEPTION_LOAD_MISALIGNED: usize = 4;
+
+// 用户态产生的所有陷入。异常发生的位置是上下文中的sepc，出错的地址或指令是stval
 #[repr(C)]
 pub enum ResumeResult<'a> {
     Syscall(&'a mut UserContext),
+    SoftwareInterrupt(&'a mut UserContext),
+    TimerInterrupt(&'a mut UserContext),
+    ExternalInterrupt(&'a mut UserContext),
+    Breakpoint(&'a mut UserContext),
+    InstructionMisaligned(&'a mut UserContext, usize),
+    InstructionAccessFault(&'a mut UserContext, usize),
+    IllegalInstruction(&'a mut UserContext, usize),
+    LoadMisaligned(&'a mut UserContext, usize),
     LoadAccessFault(&'a mut UserContext, usize),
+    StoreMisaligned(&'a mut UserContext, usize),
     StoreAccessFault(&'a mut UserContext, usize),
-    IllegalInstruction(&'a mut UserContext),
+    InstructionPageFault(&'a mut UserContext, usize),
+    LoadPageFault(&'a mut UserContext, usize),
+    StorePageFault(&'a mut UserContext, usize),
+    Unknown(&'a mut UserContext, usize, usize), // scause, stval
 }
 
 // 如果采用user_trap_handler的设计，这里的每个enum条件不能有两个参数，两个参数会导致返回值大于两个usize长度，
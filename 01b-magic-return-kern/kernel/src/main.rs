@@ -66,8 +66,60 @@ fn execute(rt: &mut executor::Runtime) -> ! {
                 rt.reset();
                 rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
             },
-            ResumeResult::IllegalInstruction(ctx) => {
-                println!("[kernel] Illegal instruction in {:#x}, core dumped.", ctx.sepc);
+            ResumeResult::IllegalInstruction(ctx, a) => {
+                println!("[kernel] Illegal instruction {:x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::InstructionMisaligned(ctx, a) => {
+                println!("[kernel] Misaligned instruction fetch from {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::InstructionAccessFault(ctx, a) => {
+                println!("[kernel] Instruction access fault to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::LoadMisaligned(ctx, a) => {
+                println!("[kernel] Misaligned load from {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::StoreMisaligned(ctx, a) => {
+                println!("[kernel] Misaligned store to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            // 这个内核没有开启分页，出现缺页异常说明应用程序出了问题
+            ResumeResult::InstructionPageFault(ctx, a) => {
+                println!("[kernel] Instruction page fault to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::LoadPageFault(ctx, a) => {
+                println!("[kernel] Load page fault to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::StorePageFault(ctx, a) => {
+                println!("[kernel] Store page fault to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
+                rt.reset();
+                rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
+            },
+            ResumeResult::Breakpoint(ctx) => {
+                // 断点不是错误，跳过ebreak指令继续运行。压缩指令c.ebreak长2个字节，它的低两位不是0b11
+                println!("[kernel] Breakpoint at {:#x}, continue.", ctx.sepc);
+                let inst = unsafe { (ctx.sepc as *const u16).read_volatile() };
+                let len = if inst & 0b11 == 0b11 { 4 } else { 2 };
+                ctx.sepc = ctx.sepc.wrapping_add(len);
+            },
+            // 内核没有打开任何中断，收到中断时关闭或清除它，然后继续运行
+            ResumeResult::SoftwareInterrupt(_ctx) => unsafe { riscv::register::sip::clear_ssoft() },
+            ResumeResult::TimerInterrupt(_ctx) => unsafe { riscv::register::sie::clear_stimer() },
+            ResumeResult::ExternalInterrupt(_ctx) => unsafe { riscv::register::sie::clear_sext() },
+            ResumeResult::Unknown(ctx, scause, a) => {
+                println!("[kernel] Unknown trap {:#x}, stval {:#x} in {:#x}, core dumped.", scause, a, ctx.sepc);
                 rt.reset();
                 rt.context_mut().sepc = app::APP_MANAGER.prepare_next_app();
             },
@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate mmu_user;
+
+// 使用浮点单元的应用程序。在rv64gc上，内核第一次遇到浮点指令时才打开浮点单元，并在切换应用程序时保存浮点寄存器
+#[no_mangle]
+fn main() -> i32 {
+    let mut sum = 0.0f64;
+    for i in 1..=100 {
+        sum += 1.0 / (i as f64);
+    }
+    let expected = 5.187377517639621;
+    if (sum - expected) * (sum - expected) > 1e-20 {
+        println!("Float sum {} differs from {}", sum, expected);
+        return 1
+    }
+    println!("Float sum of 1/n for n up to 100: {}", sum);
+    0
+}
@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate mmu_user;
+
+// 主动让出处理核。内核的执行器在这时运行其它任务，再回到这个应用程序
+#[no_mangle]
+fn main() -> i32 {
+    for i in 0..3 {
+        println!("Yield round {}", i);
+        mmu_user::yield_now();
+    }
+    0
+}
@@ -41,3 +41,14 @@ use syscall::*;
 
 pub fn write(fd: usize, buf: &[u8]) -> SyscallResult { sys_write(fd, buf) }
 pub fn exit(exit_code: i32) -> SyscallResult { sys_exit(exit_code) }
+// 让出处理核，内核运行其它的任务后再回到这里
+pub fn yield_now() -> SyscallResult { sys_yield() }
+// 修改从addr开始len字节内存的访问权限；失败时code为负数的错误码
+pub fn protect(addr: usize, len: usize, prot: usize) -> SyscallResult { sys_protect(addr, len, prot) }
+// 创建len字节的共享内存，成功时extra为句柄；句柄在关闭之前可以被其它应用程序映射
+pub fn share_create(len: usize) -> SyscallResult { sys_share_create(len) }
+pub fn share_map(handle: usize, addr: usize, prot: usize) -> SyscallResult { sys_share_map(handle, addr, prot) }
+pub fn share_unmap(addr: usize) -> SyscallResult { sys_share_unmap(addr) }
+pub fn share_close(handle: usize) -> SyscallResult { sys_share_close(handle) }
+
+pub use syscall::{PROT_READ, PROT_WRITE, PROT_EXEC};
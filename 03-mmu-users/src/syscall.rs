@@ -1,10 +1,22 @@
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+const FUNCTION_PROCESS_YIELD: usize = 0x1919811;
 
 const MODULE_TEST_INTERFACE: usize = 0x233666;
 const FUNCTION_TEST_WRITE: usize = 0x666233;
 
+const MODULE_MEMORY: usize = 0x10086;
+const FUNCTION_MEMORY_PROTECT: usize = 0x10010;
+const FUNCTION_MEMORY_SHARE_CREATE: usize = 0x10011;
+const FUNCTION_MEMORY_SHARE_MAP: usize = 0x10012;
+const FUNCTION_MEMORY_SHARE_UNMAP: usize = 0x10013;
+const FUNCTION_MEMORY_SHARE_CLOSE: usize = 0x10014;
+
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
 pub struct SyscallResult {
     pub code: usize,
     pub extra: usize,
@@ -78,10 +90,34 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> SyscallResult {
     syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_protect(addr: usize, len: usize, prot: usize) -> SyscallResult {
+    syscall_3(MODULE_MEMORY, FUNCTION_MEMORY_PROTECT, [addr, len, prot])
+}
+
+pub fn sys_share_create(len: usize) -> SyscallResult {
+    syscall_1(MODULE_MEMORY, FUNCTION_MEMORY_SHARE_CREATE, len)
+}
+
+pub fn sys_share_map(handle: usize, addr: usize, prot: usize) -> SyscallResult {
+    syscall_3(MODULE_MEMORY, FUNCTION_MEMORY_SHARE_MAP, [handle, addr, prot])
+}
+
+pub fn sys_share_unmap(addr: usize) -> SyscallResult {
+    syscall_1(MODULE_MEMORY, FUNCTION_MEMORY_SHARE_UNMAP, addr)
+}
+
+pub fn sys_share_close(handle: usize) -> SyscallResult {
+    syscall_1(MODULE_MEMORY, FUNCTION_MEMORY_SHARE_CLOSE, handle)
+}
+
 pub fn sys_exit(exit_code: i32) -> SyscallResult {
     syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_EXIT, exit_code as usize)
 }
 
+pub fn sys_yield() -> SyscallResult {
+    syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_YIELD, 0)
+}
+
 pub fn sys_panic(file_name: Option<&str>, line: u32, col: u32, msg: Option<&str>) -> SyscallResult {
     let (f_buf, f_len) = file_name.map(|s| (s.as_ptr() as usize, s.len())).unwrap_or((0, 0));
     let (m_buf, m_len) = msg.map(|s| (s.as_ptr() as usize, s.len())).unwrap_or((0, 0));
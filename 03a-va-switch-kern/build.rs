@@ -6,6 +6,7 @@ use std::path::PathBuf;
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=src/linker64.ld");
+    println!("cargo:rerun-if-changed=src/linker32.ld");
 
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
@@ -13,15 +14,23 @@ fn main() {
         .unwrap()
         .write_all(include_bytes!("src/linker64.ld"))
         .unwrap();
+    fs::File::create(out_dir.join("linker32.ld"))
+        .unwrap()
+        .write_all(include_bytes!("src/linker32.ld"))
+        .unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
 
     println!("cargo:rerun-if-changed=../03-mmu-users/src/");
-    let target_dir = "target/riscv64imac-unknown-none-elf/debug/";
+    let target = env::var("TARGET").unwrap();
+    let target_dir = format!("target/{}/debug/", target);
         // .to_string_lossy().replace("\\", "\\\\"); // 转义
-    insert_app_data(&target_dir).unwrap();
+    // 应用程序元数据的每一项都是usize，需要和目标的位宽一致
+    let pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
+    let (align, word) = if pointer_width == "32" { (2, ".word") } else { (3, ".quad") };
+    insert_app_data(&target_dir, align, word).unwrap();
 }
 
-fn insert_app_data(target_dir: &str) -> Result<()> {
+fn insert_app_data(target_dir: &str, align: usize, word: &str) -> Result<()> {
     let mut f = File::create("src/link_apps.S").unwrap();
     let mut apps: Vec<_> = read_dir("../03-mmu-users/src/bin")
         .unwrap()
@@ -34,20 +43,28 @@ fn insert_app_data(target_dir: &str) -> Result<()> {
         .collect();
     apps.sort();
 
+    // 应用程序的数量，然后是每个应用程序开始的地址，最后是最后一个应用程序结束的地址；内核按下标读取
     writeln!(f, r#"
-    .align 3
     .section .data
+    .align {}
     .global _app_meta
 _app_meta:
-    .quad {}
-    "#, apps.len())?;
+    {} {}"#, align, word, apps.len())?;
+    for i in 0..apps.len() {
+        writeln!(f, r#"    {} app_{}_start"#, word, i)?;
+    }
+    writeln!(f, r#"    {} app_{}_end"#, word, apps.len() - 1)?;
 
-    for (i, name_with_ext) in apps.iter().enumerate() {
-        writeln!(f, r#"    .quad {}"#, name_with_ext.len())?;
-        writeln!(f, r#"    .asciz "{}""#, name_with_ext)?;
-        writeln!(f, r#"    .quad app_{}_start"#, i)?;
+    // 应用程序的名称，每一项是名称的长度和名称本身，下一项按字对齐
+    writeln!(f, r#"
+    .align {}
+    .global _app_names
+_app_names:"#, align)?;
+    for name in apps.iter() {
+        writeln!(f, r#"    {} {}"#, word, name.len())?;
+        writeln!(f, r#"    .ascii "{}""#, name)?;
+        writeln!(f, r#"    .align {}"#, align)?;
     }
-    writeln!(f, r#"    .quad app_{}_end"#, apps.len() - 1)?;
 
     for (idx, app) in apps.iter().enumerate() {
         println!("app_{}: {}", idx, app);
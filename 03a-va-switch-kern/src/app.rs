@@ -1,8 +1,6 @@
 use core::cell::RefCell;
 
 const MAX_APP_NUM: usize = 16;
-const APP_BASE_ADDRESS: usize = 0x80400000;
-const APP_SIZE_LIMIT: usize = 0x20000;
 
 pub struct AppManager {
     inner: RefCell<AppManagerInner>,
@@ -14,6 +12,7 @@ impl AppManager {
     pub fn new() -> AppManager {
         AppManager {
             inner: RefCell::new({
+                // build.rs生成的表：应用程序的数量，每个应用程序开始的地址，最后是最后一个应用程序结束的地址
                 extern "C" { fn _app_meta(); }
                 let num_app_ptr = _app_meta as usize as *const usize;
                 let num_app = unsafe { num_app_ptr.read_volatile() };
@@ -24,7 +23,6 @@ impl AppManager {
                 app_start[..=num_app].copy_from_slice(app_start_raw);
                 AppManagerInner {
                     num_app,
-                    current_app: 0,
                     app_start,
                 }
             }),
@@ -39,56 +37,26 @@ impl AppManager {
         }
     } 
 
-    pub fn prepare_next_app(&self) -> usize {
-        let mut inner = self.inner.borrow_mut();
-        let current_app = inner.get_current_app_index();
-        unsafe {
-            inner.load_app(current_app);
-        }
-        inner.move_to_next_app();
-        APP_BASE_ADDRESS
+    pub fn num_app(&self) -> usize {
+        self.inner.borrow().num_app
+    }
+
+    // 得到应用程序的二进制数据。每个应用程序被复制到自己地址空间的页帧中，不再加载到固定的物理地址
+    pub fn app_data(&self, app_id: usize) -> &'static [u8] {
+        let inner = self.inner.borrow();
+        assert!(app_id < inner.num_app, "app id {} out of range", app_id);
+        unsafe { core::slice::from_raw_parts(
+            inner.app_start[app_id] as *const u8,
+            inner.app_start[app_id + 1] - inner.app_start[app_id]
+        ) }
     }
 }
 
 struct AppManagerInner {
     num_app: usize,
-    current_app: usize,
     app_start: [usize; MAX_APP_NUM + 1],
 }
 
-impl AppManagerInner {
-    unsafe fn load_app(&self, app_id: usize) {
-        if app_id >= self.num_app {
-            println!("All applications completed, shutdown!");
-            crate::sbi::shutdown();
-        }
-        println!("[kernel] Loading app_{}", app_id);
-        // clear icache
-        asm!("fence.i");
-        // clear app area
-        (APP_BASE_ADDRESS..APP_BASE_ADDRESS + APP_SIZE_LIMIT).for_each(|addr| {
-            (addr as *mut u8).write_volatile(0);
-        });
-        let app_src = core::slice::from_raw_parts(
-            self.app_start[app_id] as *const u8,
-            self.app_start[app_id + 1] - self.app_start[app_id]
-        );
-        let app_dst = core::slice::from_raw_parts_mut(
-            APP_BASE_ADDRESS as *mut u8,
-            app_src.len()
-        );
-        app_dst.copy_from_slice(app_src);
-    }
-
-    pub fn get_current_app_index(&self) -> usize { 
-        self.current_app 
-    }
-
-    pub fn move_to_next_app(&mut self) {
-        self.current_app += 1;
-    }
-}
-
 lazy_static::lazy_static! {
     pub static ref APP_MANAGER: AppManager = AppManager::new();
 }
@@ -0,0 +1,266 @@
+// 内核的启动过程。入口函数使用启动页表进入高地址，rust_main从设备树得到内存布局，初始化页帧分配器，再为内核建立分页
+use crate::{dtb, executor, loader, mm, process, FRAME_ALLOC};
+
+pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
+    extern "C" { fn sbss(); fn ebss(); fn ekernel(); }
+    unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
+    println!("[kernel] Hart id = {}, DTB physical address = {:#x}", hartid, dtb_pa);
+    mm::heap_init();
+    // 从设备树中得到物理内存和处理核的布局。启动页表已经映射了设备树所在的物理内存
+    let dtb = unsafe { dtb::from_addr(mm::PhysAddr(dtb_pa).to_virt().0) }.expect("read device tree");
+    let device_info = dtb::parse(dtb).expect("parse device tree");
+    println!("[kernel] Memory: {:x?}, reserved: {:x?}", device_info.memory, device_info.reserved);
+    println!("[kernel] Cpu count: {}, timebase frequency: {:?}, bootargs: {:?}", 
+        device_info.cpu_count, device_info.timebase_frequency, device_info.bootargs);
+    // 时钟中断按这个频率计时，用来给应用程序分配时间片
+    let timebase_frequency = device_info.timebase_frequency.expect("timebase frequency of cpus");
+    #[cfg(feature = "self-test")] {
+        mm::test_frame_alloc();
+        mm::test_bitmap_frame_alloc();
+    }
+
+    /* Test app loader */
+    let apps = loader::AppLoader::new();
+    println!("{:?}", apps);
+
+    // 页帧分配器管理内核所在的一段物理内存中，内核之后的部分；应用程序被复制到分配的页帧中，并避开保留的内存和设备树本身
+    let memory = device_info.memory.iter().find(|region| region.contains(&mm::MEMORY_START))
+        .expect("memory region containing the kernel");
+    let memory_end = usize::min(memory.end, mm::MEMORY_LIMIT);
+    let kernel_end = mm::VirtAddr(ekernel as usize).to_phys().0;
+    let from = mm::PhysAddr(kernel_end).page_number::<mm::DefaultPageMode>();
+    let to = mm::PhysAddr(memory_end).page_number::<mm::DefaultPageMode>();
+    let mut frames = mm::BitmapFrameAllocator::new(from, to);
+    for region in device_info.reserved.iter().chain(core::iter::once(&(dtb_pa..dtb_pa + dtb.len()))) {
+        let end = region.end.saturating_add(0xfff); // 向上取整到页
+        frames.reserve(mm::PhysAddr(region.start).page_number::<mm::DefaultPageMode>(), mm::PhysAddr(end).page_number::<mm::DefaultPageMode>());
+    }
+    let frame_alloc: &'static mm::DefaultFrameAllocator = FRAME_ALLOC.call_once(|| spin::Mutex::new(frames));
+    // 内核堆用完以后，从页帧分配器得到更多的页帧
+    mm::heap_grow_from(frame_alloc);
+    #[cfg(feature = "self-test")] {
+        mm::test_heap_grow();
+        mm::test_map_solve();
+    }
+    // println!("[kernel-frame] Frame allocator: {:x?}", frame_alloc);
+    #[cfg(target_pointer_width = "64")] {
+        // 选择平台支持的最大分页模式，以得到最大的用户地址空间
+        use riscv::register::satp::Mode;
+        let page_mode = mm::probe_page_mode();
+        println!("[kernel] Page mode: {:?}", page_mode);
+        match page_mode {
+            Mode::Sv57 => boot_paged(mm::Sv57, mm::activate_paged_riscv_sv57, frame_alloc, memory_end, timebase_frequency),
+            Mode::Sv48 => boot_paged(mm::Sv48, mm::activate_paged_riscv_sv48, frame_alloc, memory_end, timebase_frequency),
+            Mode::Sv39 => boot_paged(mm::Sv39, mm::activate_paged_riscv_sv39, frame_alloc, memory_end, timebase_frequency),
+            mode => panic!("unsupported page mode {:?}", mode),
+        }
+    }
+    // RV32下只有Sv32一种分页模式
+    #[cfg(target_pointer_width = "32")]
+    boot_paged(mm::Sv32, mm::activate_paged_riscv_sv32, frame_alloc, memory_end, timebase_frequency)
+}
+
+fn boot_paged<M: mm::PageMode<Flags = mm::Sv39Flags> + Unpin + 'static>(
+    page_mode: M, 
+    activate: unsafe fn(mm::PhysPageNum, mm::AddressSpaceId), 
+    frame_alloc: &'static mm::DefaultFrameAllocator,
+    memory_end: usize,
+    timebase_frequency: usize
+) -> ! {
+    let mut kernel_memory_set = mm::MemorySet::try_new_in(page_mode, frame_alloc)
+        .expect("allocate page to create kernel paged address space");
+    // println!("[kernel] Kernel memory set: {:x?}", kernel_memory_set);
+    // 线性映射所有的物理内存，内核运行在其中的高地址上。内核的每个段按各自的权限映射，
+    // 代码段不可写，数据段不可执行；内核以外的物理内存可读写
+    extern "C" {
+        fn skernel(); fn stext(); fn etext(); fn srodata(); fn erodata();
+        fn sdata(); fn edata(); fn sbss(); fn ebss(); fn ekernel();
+        fn strampoline(); fn etrampoline();
+    }
+    println!("[kernel] .text [{:#x}, {:#x}), trampoline [{:#x}, {:#x})", 
+        stext as usize, etext as usize, strampoline as usize, etrampoline as usize);
+    println!("[kernel] .rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
+    println!("[kernel] .data [{:#x}, {:#x})", sdata as usize, edata as usize);
+    println!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
+    use mm::{Sv39Flags as F, AreaKind};
+    let memory_start = mm::PhysAddr(mm::MEMORY_START).to_virt().0;
+    let memory_end = mm::PhysAddr(memory_end).to_virt().0;
+    let set = &mut kernel_memory_set;
+    map_linear(set, AreaKind::Linear, memory_start, skernel as usize, F::R | F::W | F::G);
+    map_linear(set, AreaKind::Text, stext as usize, etext as usize, F::R | F::X | F::G);
+    map_linear(set, AreaKind::Rodata, srodata as usize, erodata as usize, F::R | F::G);
+    map_linear(set, AreaKind::Data, sdata as usize, edata as usize, F::R | F::W | F::G);
+    // 启动栈在.bss段的开头。每个核的启动栈下方留出一个保护页，栈溢出时产生缺页异常，而不是改写另一个核的栈。
+    // 内核还没有自己的陷入处理函数，stvec始终指向用户的陷入入口，因此内核栈溢出无法被报告，只能保证不悄悄破坏其它数据
+    let boot_stack = unsafe { BOOT_STACK.as_ptr() as usize };
+    for hart_stack in (boot_stack..boot_stack + BOOT_STACK_SIZE).step_by(BOOT_STACK_SIZE_PER_HART) {
+        let stack_bottom = mm::VirtAddr(hart_stack + 0x1000);
+        set.map_stack_direct(
+            stack_bottom.page_number::<M>(), 
+            stack_bottom.to_phys().page_number::<M>(), 
+            BOOT_STACK_SIZE_PER_HART / 0x1000 - 1, 
+            F::R | F::W | F::G
+        ).expect("map boot stack");
+    }
+    map_linear(set, AreaKind::Bss, boot_stack + BOOT_STACK_SIZE, ekernel as usize, F::R | F::W | F::G); // 包括堆
+    map_linear(set, AreaKind::Linear, ekernel as usize, memory_end, F::R | F::W | F::G);
+    // 内核的映射都是全局映射，切换地址空间编号时不需要刷新；以后创建的用户地址空间共享覆盖它们的根页表项
+    set.addr_space_mut().set_global(mm::VirtAddr(memory_start).page_number::<M>(), memory_end.wrapping_sub(memory_start) / 0x1000);
+    // println!("[kernel] Kernel memory set: {:x?}", kernel_memory_set);
+    let stats = frame_alloc.lock().stats();
+    println!("[kernel-frame] Free frames: {}/{}, free extents: {}, fragmentation: {}%", 
+        stats.free_frames, stats.total_frames, stats.free_extents, stats.fragmentation_percent());
+    println!("[kernel-heap] Heap: {:?}", mm::heap_stats());
+    #[cfg(feature = "self-test")] {
+        mm::test_asid_alloc();
+        mm::test_asid_manager();
+    }
+    let max_asid = mm::max_asid();
+    println!("[kernel-asid] Max asid: {:?}", max_asid);
+    mm::ASID_MANAGER.lock().set_max_asid(max_asid);
+    for area in kernel_memory_set.areas() {
+        println!("[kernel] Area {:?} [{:#x}, {:#x}) {:?}", area.kind, 
+            area.range.start.addr_begin::<M>().0, area.range.end.addr_begin::<M>().0, area.flags);
+    }
+    // 内核的地址空间只有全局映射，它的地址空间编号和用户的相同也不会用错页表缓存
+    unsafe {
+        kernel_memory_set.addr_space_mut().activate(activate);
+    }
+    executor::init();
+    process::execute(&kernel_memory_set, process::USER_STACK_TOP, timebase_frequency);
+}
+
+// 映射线性映射中从start到end的虚拟地址，记录为kind区域
+fn map_linear<M: mm::PageMode<Flags = mm::Sv39Flags>>(
+    memory_set: &mut mm::MemorySet<M, &mm::DefaultFrameAllocator>, 
+    kind: mm::AreaKind,
+    start: usize, 
+    end: usize, 
+    flags: mm::Sv39Flags
+) {
+    memory_set.map_direct(
+        kind,
+        mm::VirtAddr(start).page_number::<M>(), 
+        mm::VirtAddr(start).to_phys().page_number::<M>(), 
+        end.wrapping_sub(start) / 0x1000, // 物理内存到达线性映射的上限时，end回绕到地址空间的最顶端，也就是0
+        flags
+    ).expect("allocate one mapped space");
+}
+
+// 每个核的启动栈大小，需要和entry函数中的计算一致
+const BOOT_STACK_SIZE_PER_HART: usize = 4096 * 4;
+const BOOT_STACK_SIZE: usize = BOOT_STACK_SIZE_PER_HART * 8;
+
+#[link_section = ".bss.stack"]
+static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+// 启动时使用的页表。内核链接在高地址，但被加载到物理地址上运行，需要先开启分页才能进入rust_main。
+// 页表对等映射内核所在的物理内存，使写入satp之后的下一条指令仍能执行；
+// 同时把同一段物理内存映射到加上mm::PHYS_VIRT_OFFSET的高地址。所有页表项都设置了A和D位
+#[cfg(target_pointer_width = "64")]
+#[repr(C, align(4096))]
+struct BootPageTable([usize; 512]);
+
+// Sv39页表，第2、3项是0x80000000开始的两个1G大页，第510、511项把同样的物理内存映射到0xffffffff80000000。
+// 映射的范围就是mm::MEMORY_LIMIT以下的物理内存，设备树也在其中
+#[cfg(target_pointer_width = "64")]
+static BOOT_PAGE_TABLE: BootPageTable = {
+    let mut table = [0; 512];
+    table[2] = (0x80000 << 10) | 0xcf; // V|R|W|X|A|D
+    table[3] = (0xc0000 << 10) | 0xcf;
+    table[510] = (0x80000 << 10) | 0xcf;
+    table[511] = (0xc0000 << 10) | 0xcf;
+    BootPageTable(table)
+};
+
+#[cfg(target_pointer_width = "32")]
+#[repr(C, align(4096))]
+struct BootPageTable([usize; 1024]);
+
+// Sv32页表，用4M大页把0x80000000开始的1G物理内存分别映射到0x80000000和0xc0000000，
+// 映射的范围就是mm::MEMORY_LIMIT以下的物理内存
+#[cfg(target_pointer_width = "32")]
+static BOOT_PAGE_TABLE: BootPageTable = {
+    let mut table = [0; 1024];
+    let mut i = 0;
+    while i < 256 {
+        table[0x200 + i] = ((0x80000 + (i << 10)) << 10) | 0xcf; // V|R|W|X|A|D
+        table[0x300 + i] = ((0x80000 + (i << 10)) << 10) | 0xcf;
+        i += 1;
+    }
+    BootPageTable(table)
+};
+
+#[cfg(target_pointer_width = "64")]
+#[naked]
+#[link_section = ".text.entry"] 
+#[export_name = "_start"]
+unsafe extern "C" fn entry() -> ! {
+    asm!("
+    # 1. set sp
+    # sp = bootstack + (hartid + 1) * 0x10000
+    add     t0, a0, 1
+    slli    t0, t0, 14
+1:  auipc   sp, %pcrel_hi({boot_stack})
+    addi    sp, sp, %pcrel_lo(1b)
+    add     sp, sp, t0
+
+    # 2. enable paging with boot page table (Sv39)
+1:  auipc   t0, %pcrel_hi({boot_page_table})
+    addi    t0, t0, %pcrel_lo(1b)
+    srli    t0, t0, 12
+    li      t1, 8 << 60
+    or      t0, t0, t1
+    csrw    satp, t0
+    sfence.vma
+
+    # 3. move sp to high address, jump to rust_main (high address)
+    li      t1, 0xffffffff00000000 # mm::PHYS_VIRT_OFFSET
+    add     sp, sp, t1
+1:  auipc   t0, %pcrel_hi({rust_main})
+    addi    t0, t0, %pcrel_lo(1b)
+    add     t0, t0, t1
+    jr      t0
+    ", 
+    boot_stack = sym BOOT_STACK, 
+    boot_page_table = sym BOOT_PAGE_TABLE,
+    rust_main = sym rust_main,
+    options(noreturn))
+}
+
+#[cfg(target_pointer_width = "32")]
+#[naked]
+#[link_section = ".text.entry"] 
+#[export_name = "_start"]
+unsafe extern "C" fn entry() -> ! {
+    asm!("
+    # 1. set sp
+    # sp = bootstack + (hartid + 1) * 0x10000
+    add     t0, a0, 1
+    slli    t0, t0, 14
+1:  auipc   sp, %pcrel_hi({boot_stack})
+    addi    sp, sp, %pcrel_lo(1b)
+    add     sp, sp, t0
+
+    # 2. enable paging with boot page table (Sv32)
+1:  auipc   t0, %pcrel_hi({boot_page_table})
+    addi    t0, t0, %pcrel_lo(1b)
+    srli    t0, t0, 12
+    li      t1, 1 << 31
+    or      t0, t0, t1
+    csrw    satp, t0
+    sfence.vma
+
+    # 3. move sp to high address, jump to rust_main (high address)
+    li      t1, 0x40000000 # mm::PHYS_VIRT_OFFSET
+    add     sp, sp, t1
+1:  auipc   t0, %pcrel_hi({rust_main})
+    addi    t0, t0, %pcrel_lo(1b)
+    add     t0, t0, t1
+    jr      t0
+    ", 
+    boot_stack = sym BOOT_STACK, 
+    boot_page_table = sym BOOT_PAGE_TABLE,
+    rust_main = sym rust_main,
+    options(noreturn))
+}
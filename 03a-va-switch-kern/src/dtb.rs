@@ -0,0 +1,197 @@
+//! 设备树模块
+//!
+//! 解析启动时得到的扁平设备树（FDT），得到物理内存、保留内存和处理核的布局。
+//! 只实现内核启动需要的部分，不是完整的设备树解析器
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+// 设备树头的长度，共有10个大端序的32位字段
+const HEADER_SIZE: usize = 40;
+
+// 从设备树中得到的平台信息
+#[derive(Clone, Debug, Default)]
+pub struct DeviceInfo {
+    // 物理内存的区间
+    pub memory: Vec<Range<usize>>,
+    // 保留的物理内存区间，包括内存保留表和/reserved-memory节点中的区间
+    pub reserved: Vec<Range<usize>>,
+    // 处理核的数量，不包括被禁用的核
+    pub cpu_count: usize,
+    // 时钟的频率，即time寄存器每秒增加的次数
+    pub timebase_frequency: Option<usize>,
+    // /chosen节点中的启动参数
+    pub bootargs: Option<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DtbError {
+    // 开头的魔数不对，这不是一个设备树
+    BadMagic,
+    // 设备树的长度不够，或者结构块中的偏移量超出了范围
+    Truncated,
+    // 结构块中出现了不认识的标记
+    BadToken(u32),
+}
+
+// 得到地址va上的整个设备树，设备树的长度从设备树头中读出
+//
+// 调用者需要保证从va开始的整个设备树都可以访问，并且在使用期间不被修改
+pub unsafe fn from_addr(va: usize) -> Result<&'static [u8], DtbError> {
+    let header = core::slice::from_raw_parts(va as *const u8, HEADER_SIZE);
+    Ok(core::slice::from_raw_parts(va as *const u8, total_size(header)?))
+}
+
+fn total_size(dtb: &[u8]) -> Result<usize, DtbError> {
+    if read_u32(dtb, 0)? != FDT_MAGIC {
+        return Err(DtbError::BadMagic)
+    }
+    Ok(read_u32(dtb, 4)? as usize)
+}
+
+// 解析一段内存中的设备树
+pub fn parse(dtb: &[u8]) -> Result<DeviceInfo, DtbError> {
+    let total_size = total_size(dtb)?;
+    let dtb = dtb.get(..total_size).ok_or(DtbError::Truncated)?;
+    let off_struct = read_u32(dtb, 8)? as usize;
+    let off_strings = read_u32(dtb, 12)? as usize;
+    let off_rsvmap = read_u32(dtb, 16)? as usize;
+    let mut info = DeviceInfo::default();
+    // 内存保留表，每一项是64位的地址和长度，以全零的一项结束
+    let mut offset = off_rsvmap;
+    loop {
+        let (address, size) = (read_u64(dtb, offset)? as usize, read_u64(dtb, offset + 8)? as usize);
+        if address == 0 && size == 0 {
+            break
+        }
+        info.reserved.push(address..address + size);
+        offset += 16;
+    }
+    // 结构块。nodes保存从根节点到当前节点路径上的每一个节点
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut offset = off_struct;
+    loop {
+        let token = read_u32(dtb, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = read_str(dtb, offset)?;
+                offset = align4(offset + name.len() + 1);
+                // 节点名中@之后的单元地址不影响路径的匹配；子节点默认使用和父节点相同的单元数
+                let cells = nodes.last().map(|parent| parent.cells).unwrap_or((2, 1));
+                let name = name.split('@').next().unwrap_or(name);
+                nodes.push(Node { name, cells, device_type: None, disabled: false, reg: None });
+            },
+            FDT_END_NODE => {
+                let node = nodes.pop().ok_or(DtbError::BadToken(token))?;
+                // reg属性按父节点声明的单元数解释
+                let (address_cells, size_cells) = nodes.last().map(|parent| parent.cells).unwrap_or((2, 1));
+                let path: Vec<&str> = nodes.iter().map(|node| node.name).collect();
+                match (&path[..], node.reg) {
+                    ([_], Some(reg)) if node.device_type == Some("memory") || node.name == "memory" => {
+                        read_reg(reg, address_cells, size_cells, &mut info.memory)?
+                    },
+                    ([_, "reserved-memory"], Some(reg)) => read_reg(reg, address_cells, size_cells, &mut info.reserved)?,
+                    ([_, "cpus"], _) if node.device_type == Some("cpu") && !node.disabled => info.cpu_count += 1,
+                    _ => {},
+                }
+            },
+            FDT_PROP => {
+                let len = read_u32(dtb, offset)? as usize;
+                let name = read_str(dtb, off_strings + read_u32(dtb, offset + 4)? as usize)?;
+                let value = dtb.get(offset + 8..offset + 8 + len).ok_or(DtbError::Truncated)?;
+                offset = align4(offset + 8 + len);
+                let in_cpus = nodes.get(1).map(|node| node.name) == Some("cpus");
+                let in_chosen = nodes.len() == 2 && nodes[1].name == "chosen";
+                let node = nodes.last_mut().ok_or(DtbError::BadToken(token))?;
+                match name {
+                    "#address-cells" => node.cells.0 = read_u32(value, 0)? as usize,
+                    "#size-cells" => node.cells.1 = read_u32(value, 0)? as usize,
+                    "device_type" => node.device_type = Some(trim_nul(value)),
+                    "status" => node.disabled = trim_nul(value) == "disabled",
+                    "reg" => node.reg = Some(value),
+                    // 时钟频率可以写在/cpus节点里，也可以写在每个处理核的节点里
+                    "timebase-frequency" if in_cpus && info.timebase_frequency.is_none() => {
+                        info.timebase_frequency = Some(read_cells(value, 0, value.len() / 4)?)
+                    },
+                    "bootargs" if in_chosen => info.bootargs = Some(String::from(trim_nul(value))),
+                    _ => {},
+                }
+            },
+            FDT_NOP => {},
+            FDT_END => break,
+            token => return Err(DtbError::BadToken(token)),
+        }
+    }
+    Ok(info)
+}
+
+// 结构块中的一个节点。节点的用途要在节点结束、读完所有属性之后才能确定
+struct Node<'a> {
+    name: &'a str,
+    // 这个节点声明的#address-cells和#size-cells，用于解释子节点的reg属性
+    cells: (usize, usize),
+    device_type: Option<&'a str>,
+    disabled: bool,
+    reg: Option<&'a [u8]>,
+}
+
+// 按address_cells和size_cells解释reg属性中的每一个区间
+fn read_reg(reg: &[u8], address_cells: usize, size_cells: usize, out: &mut Vec<Range<usize>>) -> Result<(), DtbError> {
+    let entry_size = (address_cells + size_cells) * 4;
+    if entry_size == 0 {
+        return Ok(())
+    }
+    for entry in reg.chunks_exact(entry_size) {
+        let address = read_cells(entry, 0, address_cells)?;
+        let size = read_cells(entry, address_cells * 4, size_cells)?;
+        out.push(address..address.saturating_add(size));
+    }
+    Ok(())
+}
+
+// 读出从offset开始的n个32位单元组成的数；超出usize范围的高位被丢弃
+fn read_cells(data: &[u8], offset: usize, n: usize) -> Result<usize, DtbError> {
+    let mut ans: u64 = 0;
+    for i in 0..n {
+        ans = (ans << 32) | read_u32(data, offset + i * 4)? as u64;
+    }
+    Ok(ans as usize)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, DtbError> {
+    let bytes = data.get(offset..offset + 4).ok_or(DtbError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, DtbError> {
+    Ok(((read_u32(data, offset)? as u64) << 32) | read_u32(data, offset + 4)? as u64)
+}
+
+// 读出从offset开始、以0结尾的字符串
+fn read_str(data: &[u8], offset: usize) -> Result<&str, DtbError> {
+    let rest = data.get(offset..).ok_or(DtbError::Truncated)?;
+    let len = rest.iter().position(|&b| b == 0).ok_or(DtbError::Truncated)?;
+    Ok(core::str::from_utf8(&rest[..len]).unwrap_or(""))
+}
+
+// 字符串属性以0结尾
+fn trim_nul(value: &[u8]) -> &str {
+    let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+    core::str::from_utf8(&value[..len]).unwrap_or("")
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests;
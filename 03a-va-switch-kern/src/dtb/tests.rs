@@ -0,0 +1,120 @@
+//! 在主机上运行的设备树解析测试
+//!
+//! 测试用的设备树按照qemu virt平台的设备树，手工拼出二进制格式
+
+use super::*;
+
+// 拼出扁平设备树的二进制格式
+#[derive(Default)]
+struct FdtBuilder {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtBuilder {
+    fn begin_node(&mut self, name: &str) -> &mut Self {
+        self.structs.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.pad();
+        self
+    }
+    fn end_node(&mut self) -> &mut Self {
+        self.structs.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+        self
+    }
+    fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+        let name_offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.structs.extend_from_slice(&FDT_PROP.to_be_bytes());
+        self.structs.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structs.extend_from_slice(&name_offset.to_be_bytes());
+        self.structs.extend_from_slice(value);
+        self.pad();
+        self
+    }
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &value)
+    }
+    fn prop_str(&mut self, name: &str, value: &str) -> &mut Self {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.prop(name, &bytes)
+    }
+    fn pad(&mut self) {
+        while self.structs.len() % 4 != 0 {
+            self.structs.push(0);
+        }
+    }
+    // 设备树头之后依次是内存保留表、结构块和字符串块
+    fn finish(&mut self, reserved: &[(u64, u64)]) -> Vec<u8> {
+        self.structs.extend_from_slice(&FDT_END.to_be_bytes());
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + (reserved.len() + 1) * 16;
+        let off_strings = off_struct + self.structs.len();
+        let total_size = off_strings + self.strings.len();
+        let header = [FDT_MAGIC, total_size as u32, off_struct as u32, off_strings as u32, off_rsvmap as u32, 
+            17, 16, 0, self.strings.len() as u32, self.structs.len() as u32];
+        let mut ans: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        for &(address, size) in reserved.iter().chain(core::iter::once(&(0, 0))) {
+            ans.extend_from_slice(&address.to_be_bytes());
+            ans.extend_from_slice(&size.to_be_bytes());
+        }
+        ans.extend_from_slice(&self.structs);
+        ans.extend_from_slice(&self.strings);
+        ans
+    }
+}
+
+fn qemu_virt_dtb() -> Vec<u8> {
+    let mut b = FdtBuilder::default();
+    b.begin_node("").prop_cells("#address-cells", &[2]).prop_cells("#size-cells", &[2]);
+    b.begin_node("chosen").prop_str("bootargs", "console=ttyS0 init=/bin/sh").end_node();
+    b.begin_node("memory@80000000").prop_str("device_type", "memory")
+        .prop_cells("reg", &[0x0, 0x8000_0000, 0x0, 0x1000_0000]).end_node();
+    b.begin_node("reserved-memory").prop_cells("#address-cells", &[2]).prop_cells("#size-cells", &[2]).prop("ranges", &[]);
+    b.begin_node("mmode_resv0@80000000").prop_cells("reg", &[0x0, 0x8000_0000, 0x0, 0x4_0000]).end_node();
+    b.end_node();
+    b.begin_node("cpus").prop_cells("#address-cells", &[1]).prop_cells("#size-cells", &[0])
+        .prop_cells("timebase-frequency", &[10_000_000]);
+    for (i, status) in ["okay", "okay", "disabled"].iter().enumerate() {
+        b.begin_node(&format!("cpu@{}", i)).prop_str("device_type", "cpu").prop_cells("reg", &[i as u32])
+            .prop_str("status", status);
+        // 处理核节点中的子节点不影响处理核的计数
+        b.begin_node("interrupt-controller").prop_cells("#interrupt-cells", &[1]).end_node();
+        b.end_node();
+    }
+    b.end_node();
+    b.end_node();
+    b.finish(&[(0x87e0_0000, 0x1000)])
+}
+
+#[test]
+fn parse_qemu_virt() {
+    let dtb = qemu_virt_dtb();
+    let info = parse(&dtb).expect("parse device tree");
+    assert_eq!(info.memory, [0x8000_0000..0x9000_0000], "memory regions");
+    assert_eq!(info.reserved, [0x87e0_0000..0x87e0_1000, 0x8000_0000..0x8004_0000], "reserved regions");
+    assert_eq!(info.cpu_count, 2, "enabled cpus");
+    assert_eq!(info.timebase_frequency, Some(10_000_000), "timebase frequency");
+    assert_eq!(info.bootargs.as_deref(), Some("console=ttyS0 init=/bin/sh"), "bootargs");
+}
+
+#[test]
+fn from_addr_reads_total_size() {
+    let mut dtb = qemu_virt_dtb();
+    let len = dtb.len();
+    dtb.extend_from_slice(&[0xff; 16]); // 设备树之后的内存不属于设备树
+    let blob = unsafe { from_addr(dtb.as_ptr() as usize) }.expect("read device tree");
+    assert_eq!(blob.len(), len, "device tree length from header");
+}
+
+#[test]
+fn bad_device_tree() {
+    let mut dtb = qemu_virt_dtb();
+    assert_eq!(parse(&dtb[..dtb.len() - 1]).unwrap_err(), DtbError::Truncated, "truncated device tree");
+    dtb[0] = 0;
+    assert_eq!(parse(&dtb).unwrap_err(), DtbError::BadMagic, "bad magic");
+}
@@ -0,0 +1,117 @@
+//! ELF文件模块
+//!
+//! 解析嵌入内核的应用程序的ELF文件，得到入口地址和需要加载的段。
+//! 只实现加载应用程序需要的部分，不是完整的ELF解析器
+
+use alloc::vec::Vec;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+// 需要加载到内存中的一个段
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadSegment<'a> {
+    // 段开始的虚拟地址，不一定按页对齐
+    pub vaddr: usize,
+    // 段在内存中的长度；比文件中的数据长的部分是bss，需要清零
+    pub mem_size: usize,
+    // 段在文件中的数据
+    pub data: &'a [u8],
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+// 从ELF文件中得到的程序信息
+#[derive(Clone, Debug)]
+pub struct ElfFile<'a> {
+    // 程序的入口地址
+    pub entry: usize,
+    // 按程序头表的顺序排列的可加载段
+    pub segments: Vec<LoadSegment<'a>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    // 开头的魔数不对，这不是一个ELF文件
+    BadMagic,
+    // 文件的长度不够，或者程序头表、段的偏移量超出了范围
+    Truncated,
+    // 不是小端序的RISC-V程序，或者位宽和内核不同
+    Unsupported,
+    // 段在内存中的长度比文件中的数据短，或者段的地址区间溢出
+    BadSegment,
+}
+
+// 解析一段内存中的ELF文件。文件的位宽需要和内核一致
+pub fn parse(elf: &[u8]) -> Result<ElfFile<'_>, ElfError> {
+    if elf.get(..4).ok_or(ElfError::Truncated)? != ELF_MAGIC {
+        return Err(ElfError::BadMagic)
+    }
+    let class = *elf.get(4).ok_or(ElfError::Truncated)?;
+    let expected_class = if core::mem::size_of::<usize>() == 8 { ELFCLASS64 } else { ELFCLASS32 };
+    if class != expected_class || *elf.get(5).ok_or(ElfError::Truncated)? != ELFDATA2LSB
+        || read_u16(elf, 18)? != EM_RISCV {
+        return Err(ElfError::Unsupported)
+    }
+    let is_64 = class == ELFCLASS64;
+    // 两种位宽的文件头中，入口地址之后的字段偏移量不同
+    let (entry, ph_offset, ph_entry_size, ph_num) = if is_64 {
+        (read_u64(elf, 24)?, read_u64(elf, 32)?, read_u16(elf, 54)?, read_u16(elf, 56)?)
+    } else {
+        (read_u32(elf, 24)? as u64, read_u32(elf, 28)? as u64, read_u16(elf, 42)?, read_u16(elf, 44)?)
+    };
+    let mut segments = Vec::new();
+    for i in 0..ph_num as usize {
+        let ph = (ph_offset as usize).checked_add(i * ph_entry_size as usize).ok_or(ElfError::Truncated)?;
+        if read_u32(elf, ph)? != PT_LOAD {
+            continue
+        }
+        let (flags, offset, vaddr, file_size, mem_size) = if is_64 {
+            (read_u32(elf, ph + 4)?, read_u64(elf, ph + 8)?, read_u64(elf, ph + 16)?,
+                read_u64(elf, ph + 32)?, read_u64(elf, ph + 40)?)
+        } else {
+            (read_u32(elf, ph + 24)?, read_u32(elf, ph + 4)? as u64, read_u32(elf, ph + 8)? as u64,
+                read_u32(elf, ph + 16)? as u64, read_u32(elf, ph + 20)? as u64)
+        };
+        if file_size > mem_size || (vaddr as usize).checked_add(mem_size as usize).is_none() {
+            return Err(ElfError::BadSegment)
+        }
+        let data_end = (offset as usize).checked_add(file_size as usize).ok_or(ElfError::Truncated)?;
+        let data = elf.get(offset as usize..data_end).ok_or(ElfError::Truncated)?;
+        segments.push(LoadSegment {
+            vaddr: vaddr as usize,
+            mem_size: mem_size as usize,
+            data,
+            readable: flags & PF_R != 0,
+            writable: flags & PF_W != 0,
+            executable: flags & PF_X != 0,
+        });
+    }
+    Ok(ElfFile { entry: entry as usize, segments })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = offset.checked_add(2).and_then(|end| data.get(offset..end)).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = offset.checked_add(4).and_then(|end| data.get(offset..end)).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    Ok(read_u32(data, offset)? as u64 | (read_u32(data, offset + 4)? as u64) << 32)
+}
+
+// 测试用的ELF文件是64位的，只在64位的主机上运行
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests;
@@ -0,0 +1,81 @@
+//! 在主机上运行的ELF文件解析测试
+//!
+//! 测试用的ELF文件按照用户程序的链接脚本，手工拼出文件头和程序头表
+
+use super::*;
+
+const HEADER_SIZE: usize = 64;
+const PH_ENTRY_SIZE: usize = 56;
+const PT_NOTE: u32 = 4;
+
+// 程序头的类型、标志、文件中的数据、虚拟地址和内存中的长度
+type Segment<'a> = (u32, u32, &'a [u8], u64, u64);
+
+// 文件头之后是程序头表，然后依次是每个段的数据
+fn build_elf(entry: u64, segments: &[Segment]) -> Vec<u8> {
+    let mut ans = Vec::new();
+    ans.extend_from_slice(&ELF_MAGIC);
+    ans.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1, 0]);
+    ans.resize(16, 0);
+    ans.extend_from_slice(&2u16.to_le_bytes()); // 可执行文件
+    ans.extend_from_slice(&EM_RISCV.to_le_bytes());
+    ans.extend_from_slice(&1u32.to_le_bytes());
+    ans.extend_from_slice(&entry.to_le_bytes());
+    ans.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    ans.extend_from_slice(&0u64.to_le_bytes());
+    ans.extend_from_slice(&0u32.to_le_bytes());
+    ans.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    ans.extend_from_slice(&(PH_ENTRY_SIZE as u16).to_le_bytes());
+    ans.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    ans.extend_from_slice(&[0; 6]);
+    assert_eq!(ans.len(), HEADER_SIZE);
+    let mut offset = HEADER_SIZE + segments.len() * PH_ENTRY_SIZE;
+    for &(p_type, flags, data, vaddr, mem_size) in segments {
+        ans.extend_from_slice(&p_type.to_le_bytes());
+        ans.extend_from_slice(&flags.to_le_bytes());
+        ans.extend_from_slice(&(offset as u64).to_le_bytes());
+        ans.extend_from_slice(&vaddr.to_le_bytes());
+        ans.extend_from_slice(&vaddr.to_le_bytes());
+        ans.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        ans.extend_from_slice(&mem_size.to_le_bytes());
+        ans.extend_from_slice(&0x1000u64.to_le_bytes());
+        offset += data.len();
+    }
+    for &(_, _, data, _, _) in segments {
+        ans.extend_from_slice(data);
+    }
+    ans
+}
+
+#[test]
+fn parse_load_segments() {
+    let text = [0x13, 0, 0, 0];
+    let data = [1, 2, 3];
+    let elf = build_elf(0x1000, &[
+        (PT_LOAD, PF_R | PF_X, &text, 0x1000, 4),
+        (PT_NOTE, PF_R, &[0; 8], 0, 8),
+        (PT_LOAD, PF_R | PF_W, &data, 0x2000, 0x1800),
+    ]);
+    let file = parse(&elf).expect("parse elf");
+    assert_eq!(file.entry, 0x1000);
+    assert_eq!(file.segments, vec![
+        LoadSegment { vaddr: 0x1000, mem_size: 4, data: &text, readable: true, writable: false, executable: true },
+        LoadSegment { vaddr: 0x2000, mem_size: 0x1800, data: &data, readable: true, writable: true, executable: false },
+    ]);
+}
+
+#[test]
+fn bad_elf_file() {
+    let elf = build_elf(0x1000, &[(PT_LOAD, PF_R | PF_X, &[0; 4], 0x1000, 4)]);
+    let mut bad_magic = elf.clone();
+    bad_magic[0] = 0;
+    assert_eq!(parse(&bad_magic).unwrap_err(), ElfError::BadMagic);
+    let mut elf32 = elf.clone();
+    elf32[4] = ELFCLASS32;
+    assert_eq!(parse(&elf32).unwrap_err(), ElfError::Unsupported);
+    assert_eq!(parse(&elf[..HEADER_SIZE + 8]).unwrap_err(), ElfError::Truncated);
+    // 段的数据超出文件的末尾
+    assert_eq!(parse(&elf[..elf.len() - 1]).unwrap_err(), ElfError::Truncated);
+    let short = build_elf(0x1000, &[(PT_LOAD, PF_R | PF_W, &[0; 8], 0x2000, 4)]);
+    assert_eq!(parse(&short).unwrap_err(), ElfError::BadSegment);
+}
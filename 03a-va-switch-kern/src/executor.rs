@@ -1,12 +1,14 @@
 use riscv::register::{
-    sstatus::{self, Sstatus, SPP},
-    scause::{self, Trap, Exception},
-    stvec::{self, TrapMode}, stval,
+    sstatus::{self, SPP},
+    scause::{self, Trap, Exception, Interrupt},
+    stvec::{self, TrapMode}, stval, sie,
 };
 use core::{
     pin::Pin,
     ops::{Generator, GeneratorState},
 };
+#[cfg(target_feature = "d")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 // use crate::mm;
 
 pub fn init() {
@@ -15,27 +17,48 @@ pub fn init() {
         addr += 0x2; // 必须对齐到4个字节
     }
     unsafe { stvec::write(addr, TrapMode::Direct) };
+    // 打开时钟中断。内核运行时sstatus.SIE为0，不会被打断；回到用户态后，监管者的中断总是全局打开的
+    unsafe { sie::set_stimer() };
+    // 内核按riscv64gc的硬件浮点编译，但自己不使用浮点寄存器，运行时sstatus.FS总是Off。
+    // 明确关闭内核的浮点单元，误用浮点指令时产生异常，不会改写用户的浮点寄存器；resume函数依赖这一点，只在需要时恢复浮点寄存器
+    #[cfg(target_feature = "d")]
+    unsafe { float_off() };
 }
 
 #[repr(C)]
 pub struct Runtime {
     context: UserContext, 
+    user_stack: usize,
+    // 运行时的编号，用来判断处理核的浮点寄存器中是不是这个运行时的值
+    #[cfg(target_feature = "d")]
+    float_id: usize,
     // current_user_stack: Vec<mm::FrameBox>,
 }
 
 impl Runtime {
-    pub fn new_user(first_app_sepc: usize) -> Self {
+    pub fn new_user(first_app_sepc: usize, user_stack: usize) -> Self {
         let context: UserContext = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
-        let mut ans = Runtime { context };
+        let mut ans = Runtime {
+            context, user_stack,
+            #[cfg(target_feature = "d")]
+            float_id: NEXT_FLOAT_ID.fetch_add(1, Ordering::Relaxed),
+        };
         ans.prepare_next_app(first_app_sepc);
         ans
     }
 
     fn reset(&mut self) {
-        // self.context.sp = self.user_stack;
+        self.context.sp = self.user_stack;
         unsafe { sstatus::set_spp(SPP::User) };
-        self.context.sstatus = sstatus::read();
-        self.context.kernel_stack = 0x233333666666; // 将会被resume函数覆盖
+        // 新的应用程序关闭浮点和向量单元，第一次使用浮点单元时才打开，见resume函数
+        let sstatus: usize;
+        unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) };
+        self.context.sstatus = sstatus & !SSTATUS_FS & !SSTATUS_VS;
+        self.context.fp = FloatContext::default();
+        // 浮点寄存器中的旧值不再属于这个运行时
+        #[cfg(target_feature = "d")]
+        let _ = FLOAT_OWNER.compare_exchange(self.float_id, 0, Ordering::Relaxed, Ordering::Relaxed);
+        self.context.kernel_stack = 0x233333666666_u64 as usize; // 将会被resume函数覆盖
     }
 
     // 在处理异常的时候，使用context_mut得到运行时当前用户的上下文，可以改变上下文的内容
@@ -43,6 +66,11 @@ impl Runtime {
         &mut self.context
     }
 
+    // 设置回到用户时切换到的地址空间，satp的值由PagedAddrSpace::prepare_activate得到
+    pub fn set_user_satp(&mut self, satp: usize) {
+        self.context.user_satp = satp;
+    }
+
     pub fn prepare_next_app(&mut self, new_sepc: usize) {
         self.reset();
         self.context.sepc = new_sepc;
@@ -53,25 +81,80 @@ impl Generator for Runtime {
     type Yield = KernelTrap;
     type Return = ();
     fn resume(mut self: Pin<&mut Self>, _arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
-        unsafe { do_resume(&mut self.context as *mut _) };
-        let stval = stval::read();
-        let trap = match scause::read().cause() {
+        let (scause, stval, sepc) = loop {
+            // 内核不使用浮点寄存器，上次从这个运行时回来以后，只有其它运行时会改写它们。
+            // 用过浮点单元的运行时，只在浮点寄存器中不是自己的值时才恢复
+            #[cfg(target_feature = "d")]
+            debug_assert_eq!(kernel_float_state(), FS_OFF, "float unit is on in kernel");
+            #[cfg(target_feature = "d")]
+            if self.context.sstatus & SSTATUS_FS != FS_OFF && FLOAT_OWNER.load(Ordering::Relaxed) != self.float_id {
+                unsafe { restore_float(&self.context.fp) };
+                FLOAT_OWNER.store(self.float_id, Ordering::Relaxed);
+            }
+            unsafe { do_resume(&mut self.context as *mut _) };
+            // 用户修改过浮点寄存器时才保存，保存后标记为干净的状态
+            #[cfg(target_feature = "d")]
+            if self.context.sstatus & SSTATUS_FS == FS_DIRTY {
+                unsafe { save_float(&mut self.context.fp) };
+                self.context.sstatus = (self.context.sstatus & !SSTATUS_FS) | FS_CLEAN;
+            }
+            // 从用户回来时sstatus.FS还是用户的状态，回到内核以后重新关闭
+            #[cfg(target_feature = "d")]
+            unsafe { float_off() };
+            let scause = scause::read();
+            // 浮点单元关闭时，浮点指令产生非法指令异常。这时打开浮点单元，从全零的浮点寄存器开始，重新执行这条指令；
+            // 如果打开后仍然是非法指令，才交给内核处理。只使用整数的应用程序不需要保存和恢复浮点寄存器
+            #[cfg(target_feature = "d")]
+            if matches!(scause.cause(), Trap::Exception(Exception::IllegalInstruction)) && self.context.sstatus & SSTATUS_FS == FS_OFF {
+                self.context.sstatus |= FS_INITIAL;
+                continue
+            }
+            break (scause, stval::read(), self.context.sepc)
+        };
+        let trap = match scause.cause() {
+            Trap::Interrupt(Interrupt::SupervisorTimer) => KernelTrap::Timer(),
+            Trap::Interrupt(Interrupt::SupervisorSoft) => KernelTrap::SoftwareInterrupt(),
+            Trap::Interrupt(Interrupt::SupervisorExternal) => KernelTrap::ExternalInterrupt(),
             Trap::Exception(Exception::UserEnvCall) => KernelTrap::Syscall(),
-            Trap::Exception(Exception::LoadFault) => KernelTrap::LoadAccessFault(stval),
-            Trap::Exception(Exception::StoreFault) => KernelTrap::StoreAccessFault(stval),
-            Trap::Exception(Exception::IllegalInstruction) => KernelTrap::IllegalInstruction(stval),
-            e => panic!("unhandled exception: {:?}! stval: {:#x?}, ctx: {:#x?}", e, stval, self.context)
+            Trap::Exception(Exception::Breakpoint) => KernelTrap::Breakpoint(sepc),
+            Trap::Exception(Exception::InstructionMisaligned) => KernelTrap::InstructionMisaligned(stval, sepc),
+            Trap::Exception(Exception::InstructionFault) => KernelTrap::InstructionAccessFault(stval, sepc),
+            Trap::Exception(Exception::IllegalInstruction) => KernelTrap::IllegalInstruction(stval, sepc),
+            Trap::Exception(Exception::LoadFault) => KernelTrap::LoadAccessFault(stval, sepc),
+            Trap::Exception(Exception::StoreMisaligned) => KernelTrap::StoreMisaligned(stval, sepc),
+            Trap::Exception(Exception::StoreFault) => KernelTrap::StoreAccessFault(stval, sepc),
+            Trap::Exception(Exception::StorePageFault) => KernelTrap::StorePageFault(stval, sepc),
+            Trap::Exception(Exception::LoadPageFault) => KernelTrap::LoadPageFault(stval, sepc),
+            Trap::Exception(Exception::InstructionPageFault) => KernelTrap::InstructionPageFault(stval, sepc),
+            // riscv库的Exception不一定包含读取未对齐异常，按异常编号判断
+            _ if scause.is_exception() && scause.code() == EXCEPTION_LOAD_MISALIGNED => KernelTrap::LoadMisaligned(stval, sepc),
+            _ => KernelTrap::Unknown(scause.bits(), stval, sepc),
         };
         GeneratorState::Yielded(trap)
     }
 }
 
+const EXCEPTION_LOAD_MISALIGNED: usize = 4;
+
+// 用户态产生的所有陷入。异常带有stval和sepc，由内核决定结束应用程序、模拟执行还是继续运行
 #[repr(C)]
 pub enum KernelTrap {
     Syscall(),
-    LoadAccessFault(usize),
-    StoreAccessFault(usize),
-    IllegalInstruction(usize),
+    Timer(),
+    SoftwareInterrupt(),
+    ExternalInterrupt(),
+    Breakpoint(usize), // sepc
+    InstructionMisaligned(usize, usize), // stval, sepc
+    InstructionAccessFault(usize, usize),
+    IllegalInstruction(usize, usize),
+    LoadMisaligned(usize, usize),
+    LoadAccessFault(usize, usize),
+    StoreMisaligned(usize, usize),
+    StoreAccessFault(usize, usize),
+    StorePageFault(usize, usize),
+    LoadPageFault(usize, usize),
+    InstructionPageFault(usize, usize),
+    Unknown(usize, usize, usize), // scause, stval, sepc
 }
 
 #[derive(Debug)]
@@ -108,168 +191,282 @@ pub struct UserContext {
     pub t4: usize,
     pub t5: usize,
     pub t6: usize, // 30
-    pub sstatus: Sstatus, // 31
+    pub sstatus: usize, // 31
     pub sepc: usize, // 32
     pub kernel_stack: usize, // 33
+    pub user_satp: usize, // 34
+    pub kernel_satp: usize, // 35
+    pub fp: FloatContext, // 不由跳板页保存，在resume函数中按需保存和恢复
+}
+
+// 浮点寄存器和浮点控制状态寄存器
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct FloatContext {
+    pub f: [u64; 32],
+    pub fcsr: usize,
+}
+
+// sstatus中浮点单元和向量单元的状态。Off表示关闭，使用时产生非法指令异常；Dirty表示寄存器被修改过。
+// 向量单元一直保持关闭，用户程序使用向量指令将被当作非法指令
+const SSTATUS_FS: usize = 0b11 << 13;
+const SSTATUS_VS: usize = 0b11 << 9;
+#[cfg(target_feature = "d")]
+const FS_OFF: usize = 0b00 << 13;
+#[cfg(target_feature = "d")]
+const FS_INITIAL: usize = 0b01 << 13;
+#[cfg(target_feature = "d")]
+const FS_CLEAN: usize = 0b10 << 13;
+#[cfg(target_feature = "d")]
+const FS_DIRTY: usize = 0b11 << 13;
+
+// 处理核的浮点寄存器中保存的是哪个运行时的值；0表示不属于任何运行时。目前只在一个处理核上运行
+#[cfg(target_feature = "d")]
+static FLOAT_OWNER: AtomicUsize = AtomicUsize::new(0);
+#[cfg(target_feature = "d")]
+static NEXT_FLOAT_ID: AtomicUsize = AtomicUsize::new(1);
+
+// 保存浮点寄存器。sstatus.FS不能是Off，否则浮点指令也会产生非法指令异常；
+// 刚从用户回来时，sstatus就是用户的值，FS为Dirty
+#[cfg(target_feature = "d")]
+unsafe fn save_float(fp: &mut FloatContext) {
+    let fcsr: usize;
+    asm!(
+        "fsd    f0, 0*8({base})",  "fsd    f1, 1*8({base})",  "fsd    f2, 2*8({base})",  "fsd    f3, 3*8({base})",
+        "fsd    f4, 4*8({base})",  "fsd    f5, 5*8({base})",  "fsd    f6, 6*8({base})",  "fsd    f7, 7*8({base})",
+        "fsd    f8, 8*8({base})",  "fsd    f9, 9*8({base})",  "fsd    f10, 10*8({base})", "fsd    f11, 11*8({base})",
+        "fsd    f12, 12*8({base})", "fsd    f13, 13*8({base})", "fsd    f14, 14*8({base})", "fsd    f15, 15*8({base})",
+        "fsd    f16, 16*8({base})", "fsd    f17, 17*8({base})", "fsd    f18, 18*8({base})", "fsd    f19, 19*8({base})",
+        "fsd    f20, 20*8({base})", "fsd    f21, 21*8({base})", "fsd    f22, 22*8({base})", "fsd    f23, 23*8({base})",
+        "fsd    f24, 24*8({base})", "fsd    f25, 25*8({base})", "fsd    f26, 26*8({base})", "fsd    f27, 27*8({base})",
+        "fsd    f28, 28*8({base})", "fsd    f29, 29*8({base})", "fsd    f30, 30*8({base})", "fsd    f31, 31*8({base})",
+        "frcsr  {fcsr}",
+        base = in(reg) fp.f.as_mut_ptr(), fcsr = out(reg) fcsr,
+    );
+    fp.fcsr = fcsr;
+}
+
+// 恢复浮点寄存器。先打开浮点单元，回到用户时sstatus将被换成用户上下文中的值
+#[cfg(target_feature = "d")]
+unsafe fn restore_float(fp: &FloatContext) {
+    asm!(
+        "csrs   sstatus, {fs}",
+        "fld    f0, 0*8({base})",  "fld    f1, 1*8({base})",  "fld    f2, 2*8({base})",  "fld    f3, 3*8({base})",
+        "fld    f4, 4*8({base})",  "fld    f5, 5*8({base})",  "fld    f6, 6*8({base})",  "fld    f7, 7*8({base})",
+        "fld    f8, 8*8({base})",  "fld    f9, 9*8({base})",  "fld    f10, 10*8({base})", "fld    f11, 11*8({base})",
+        "fld    f12, 12*8({base})", "fld    f13, 13*8({base})", "fld    f14, 14*8({base})", "fld    f15, 15*8({base})",
+        "fld    f16, 16*8({base})", "fld    f17, 17*8({base})", "fld    f18, 18*8({base})", "fld    f19, 19*8({base})",
+        "fld    f20, 20*8({base})", "fld    f21, 21*8({base})", "fld    f22, 22*8({base})", "fld    f23, 23*8({base})",
+        "fld    f24, 24*8({base})", "fld    f25, 25*8({base})", "fld    f26, 26*8({base})", "fld    f27, 27*8({base})",
+        "fld    f28, 28*8({base})", "fld    f29, 29*8({base})", "fld    f30, 30*8({base})", "fld    f31, 31*8({base})",
+        "fscsr  {fcsr}",
+        base = in(reg) fp.f.as_ptr(), fcsr = in(reg) fp.fcsr, fs = in(reg) FS_INITIAL,
+    );
+}
+
+// 关闭当前的浮点单元，内核运行时sstatus.FS总是Off
+#[cfg(target_feature = "d")]
+unsafe fn float_off() {
+    asm!("csrc   sstatus, {fs}", fs = in(reg) SSTATUS_FS);
+}
+
+// 内核当前的浮点单元状态
+#[cfg(target_feature = "d")]
+fn kernel_float_state() -> usize {
+    let sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) };
+    sstatus & SSTATUS_FS
+}
+
+// 跳板页：在用户和内核之间切换的代码都放在.text.trampoline段，链接时单独占据一页。
+// 内核的映射都是全局映射，每个用户地址空间都共享，所以跳板页和用户上下文在所有地址空间中都映射在同一地址，
+// 在这里写入satp切换地址空间后，下一条指令仍能继续执行。
+
+// 保存和恢复上下文时，每个寄存器占用XLEN/8个字节；RV64下使用sd/ld指令，RV32下使用sw/lw指令
+#[cfg(target_pointer_width = "64")]
+macro_rules! xlenb { () => { "8" } }
+#[cfg(target_pointer_width = "64")]
+macro_rules! sx { () => { "sd" } }
+#[cfg(target_pointer_width = "64")]
+macro_rules! lx { () => { "ld" } }
+#[cfg(target_pointer_width = "32")]
+macro_rules! xlenb { () => { "4" } }
+#[cfg(target_pointer_width = "32")]
+macro_rules! sx { () => { "sw" } }
+#[cfg(target_pointer_width = "32")]
+macro_rules! lx { () => { "lw" } }
+
+// store!(reg, n, base)：把reg保存到base开始的第n个寄存器的位置
+macro_rules! store {
+    ($reg: ident, $n: literal, $base: ident) => {
+        concat!(sx!(), "     ", stringify!($reg), ", ", stringify!($n), "*", xlenb!(), "(", stringify!($base), ")\n")
+    };
+}
+
+// load!(reg, n, base)：从base开始的第n个寄存器的位置恢复reg
+macro_rules! load {
+    ($reg: ident, $n: literal, $base: ident) => {
+        concat!(lx!(), "     ", stringify!($reg), ", ", stringify!($n), "*", xlenb!(), "(", stringify!($base), ")\n")
+    };
 }
 
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
 unsafe extern "C" fn do_resume(_user_context: *mut UserContext) {
     asm!("j     {from_kernel_save}", from_kernel_save = sym from_kernel_save, options(noreturn))
 }
 
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
 unsafe extern "C" fn from_kernel_save(_user_context: *mut UserContext) -> ! {
-    asm!( // sp:内核栈顶
-        "addi   sp, sp, -15*8", // sp:内核栈顶
+    asm!(concat!( // sp:内核栈顶
+        "addi   sp, sp, -15*", xlenb!(), "\n", // sp:内核栈顶
         // 进入函数之前，已经保存了调用者寄存器，应当保存被调用者寄存器
-        "sd     ra, 0*8(sp)
-        sd      gp, 1*8(sp)
-        sd      tp, 2*8(sp)
-        sd      s0, 3*8(sp)
-        sd      s1, 4*8(sp)
-        sd      s2, 5*8(sp)
-        sd      s3, 6*8(sp)
-        sd      s4, 7*8(sp)
-        sd      s5, 8*8(sp)
-        sd      s6, 9*8(sp)
-        sd      s7, 10*8(sp)
-        sd      s8, 11*8(sp)
-        sd      s9, 12*8(sp)
-        sd      s10, 13*8(sp)
-        sd      s11, 14*8(sp)", 
+        store!(ra, 0, sp),
+        store!(gp, 1, sp),
+        store!(tp, 2, sp),
+        store!(s0, 3, sp),
+        store!(s1, 4, sp),
+        store!(s2, 5, sp),
+        store!(s3, 6, sp),
+        store!(s4, 7, sp),
+        store!(s5, 8, sp),
+        store!(s6, 9, sp),
+        store!(s7, 10, sp),
+        store!(s8, 11, sp),
+        store!(s9, 12, sp),
+        store!(s10, 13, sp),
+        store!(s11, 14, sp),
         // a0:用户上下文
-        "j      {to_user_restore}",
-        to_user_restore = sym to_user_restore,
-        options(noreturn)
-    )
+        "j      {to_user_restore}\n",
+    ), to_user_restore = sym to_user_restore, options(noreturn))
 }
 
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
 pub unsafe extern "C" fn to_user_restore(_user_context: *mut UserContext) -> ! {
-    asm!( // a0:用户上下文
-        "sd     sp, 33*8(a0)", // 内核栈顶放进用户上下文
-        "csrw   sscratch, a0", // 新sscratch:用户上下文
+    asm!(concat!( // a0:用户上下文
+        store!(sp, 33, a0), // 内核栈顶放进用户上下文
+        "csrr   t0, satp\n",
+        store!(t0, 35, a0), // 内核的satp放进用户上下文
+        load!(t0, 34, a0),
+        "csrw   satp, t0\n", // 切换到用户的地址空间。地址空间编号不同，不需要刷新页表缓存
+        "csrw   sscratch, a0\n", // 新sscratch:用户上下文
         // sscratch:用户上下文
-        "mv     sp, a0", // 新sp:用户上下文
-        "ld     t0, 31*8(sp)
-        ld      t1, 32*8(sp)
-        csrw    sstatus, t0
-        csrw    sepc, t1",
-        "ld     ra, 0*8(sp)
-        ld      gp, 2*8(sp)
-        ld      tp, 3*8(sp)
-        ld      t0, 4*8(sp)
-        ld      t1, 5*8(sp)
-        ld      t2, 6*8(sp)
-        ld      s0, 7*8(sp)
-        ld      s1, 8*8(sp)
-        ld      a0, 9*8(sp)
-        ld      a1, 10*8(sp)
-        ld      a2, 11*8(sp)
-        ld      a3, 12*8(sp)
-        ld      a4, 13*8(sp)
-        ld      a5, 14*8(sp)
-        ld      a6, 15*8(sp)
-        ld      a7, 16*8(sp)
-        ld      s2, 17*8(sp)
-        ld      s3, 18*8(sp)
-        ld      s4, 19*8(sp)
-        ld      s5, 20*8(sp)
-        ld      s6, 21*8(sp)
-        ld      s7, 22*8(sp)
-        ld      s8, 23*8(sp)
-        ld      s9, 24*8(sp)
-        ld     s10, 25*8(sp)
-        ld     s11, 26*8(sp)
-        ld      t3, 27*8(sp)
-        ld      t4, 28*8(sp)
-        ld      t5, 29*8(sp)
-        ld      t6, 30*8(sp)",
-        "ld     sp, 1*8(sp)", // 新sp:用户栈
+        "mv     sp, a0\n", // 新sp:用户上下文
+        load!(t0, 31, sp),
+        load!(t1, 32, sp),
+        "csrw   sstatus, t0\n",
+        "csrw   sepc, t1\n",
+        load!(ra, 0, sp),
+        load!(gp, 2, sp),
+        load!(tp, 3, sp),
+        load!(t0, 4, sp),
+        load!(t1, 5, sp),
+        load!(t2, 6, sp),
+        load!(s0, 7, sp),
+        load!(s1, 8, sp),
+        load!(a0, 9, sp),
+        load!(a1, 10, sp),
+        load!(a2, 11, sp),
+        load!(a3, 12, sp),
+        load!(a4, 13, sp),
+        load!(a5, 14, sp),
+        load!(a6, 15, sp),
+        load!(a7, 16, sp),
+        load!(s2, 17, sp),
+        load!(s3, 18, sp),
+        load!(s4, 19, sp),
+        load!(s5, 20, sp),
+        load!(s6, 21, sp),
+        load!(s7, 22, sp),
+        load!(s8, 23, sp),
+        load!(s9, 24, sp),
+        load!(s10, 25, sp),
+        load!(s11, 26, sp),
+        load!(t3, 27, sp),
+        load!(t4, 28, sp),
+        load!(t5, 29, sp),
+        load!(t6, 30, sp),
+        load!(sp, 1, sp), // 新sp:用户栈
         // sp:用户栈, sscratch:用户上下文
-        "sret",
-        options(noreturn)
-    )
+        "sret\n",
+    ), options(noreturn))
 }
 
 // 中断开始
 
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
 pub unsafe extern "C" fn from_user_save() -> ! {
-    asm!( // sp:用户栈,sscratch:用户上下文
-        ".p2align 2",
-        "csrrw  sp, sscratch, sp", // 新sscratch:用户栈, 新sp:用户上下文
-        "sd     ra, 0*8(sp)
-        sd      gp, 2*8(sp)
-        sd      tp, 3*8(sp)
-        sd      t0, 4*8(sp)
-        sd      t1, 5*8(sp)
-        sd      t2, 6*8(sp)
-        sd      s0, 7*8(sp)
-        sd      s1, 8*8(sp)
-        sd      a0, 9*8(sp)
-        sd      a1, 10*8(sp)
-        sd      a2, 11*8(sp)
-        sd      a3, 12*8(sp)
-        sd      a4, 13*8(sp)
-        sd      a5, 14*8(sp)
-        sd      a6, 15*8(sp)
-        sd      a7, 16*8(sp)
-        sd      s2, 17*8(sp)
-        sd      s3, 18*8(sp)
-        sd      s4, 19*8(sp)
-        sd      s5, 20*8(sp)
-        sd      s6, 21*8(sp)
-        sd      s7, 22*8(sp)
-        sd      s8, 23*8(sp)
-        sd      s9, 24*8(sp)
-        sd     s10, 25*8(sp)
-        sd     s11, 26*8(sp)
-        sd      t3, 27*8(sp)
-        sd      t4, 28*8(sp)
-        sd      t5, 29*8(sp)
-        sd      t6, 30*8(sp)",
-        "csrr   t0, sstatus
-        sd      t0, 31*8(sp)",
-        "csrr   t1, sepc
-        sd      t1, 32*8(sp)",
+    asm!(concat!( // sp:用户栈,sscratch:用户上下文
+        ".p2align 2\n",
+        "csrrw  sp, sscratch, sp\n", // 新sscratch:用户栈, 新sp:用户上下文
+        store!(ra, 0, sp),
+        store!(gp, 2, sp),
+        store!(tp, 3, sp),
+        store!(t0, 4, sp),
+        store!(t1, 5, sp),
+        store!(t2, 6, sp),
+        store!(s0, 7, sp),
+        store!(s1, 8, sp),
+        store!(a0, 9, sp),
+        store!(a1, 10, sp),
+        store!(a2, 11, sp),
+        store!(a3, 12, sp),
+        store!(a4, 13, sp),
+        store!(a5, 14, sp),
+        store!(a6, 15, sp),
+        store!(a7, 16, sp),
+        store!(s2, 17, sp),
+        store!(s3, 18, sp),
+        store!(s4, 19, sp),
+        store!(s5, 20, sp),
+        store!(s6, 21, sp),
+        store!(s7, 22, sp),
+        store!(s8, 23, sp),
+        store!(s9, 24, sp),
+        store!(s10, 25, sp),
+        store!(s11, 26, sp),
+        store!(t3, 27, sp),
+        store!(t4, 28, sp),
+        store!(t5, 29, sp),
+        store!(t6, 30, sp),
+        "csrr   t0, sstatus\n",
+        store!(t0, 31, sp),
+        "csrr   t1, sepc\n",
+        store!(t1, 32, sp),
         // sscratch:用户栈,sp:用户上下文
-        "csrrw  t2, sscratch, sp", // 新sscratch:用户上下文,t2:用户栈
-        "sd     t2, 1*8(sp)", // 保存用户栈
-        "j      {to_kernel_restore}",
-        to_kernel_restore = sym to_kernel_restore,
-        options(noreturn)
-    )
+        "csrrw  t2, sscratch, sp\n", // 新sscratch:用户上下文,t2:用户栈
+        store!(t2, 1, sp), // 保存用户栈
+        load!(t0, 35, sp),
+        "csrw   satp, t0\n", // 切换回内核的地址空间
+        "j      {to_kernel_restore}\n",
+    ), to_kernel_restore = sym to_kernel_restore, options(noreturn))
 }
 
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
 unsafe extern "C" fn to_kernel_restore() -> ! {
-    asm!( // sscratch:用户上下文
-        "csrr   sp, sscratch", // sp:用户上下文
-        "ld     sp, 33*8(sp)", // sp:内核栈
-        "ld     ra, 0*8(sp)
-        ld      gp, 1*8(sp)
-        ld      tp, 2*8(sp)
-        ld      s0, 3*8(sp)
-        ld      s1, 4*8(sp)
-        ld      s2, 5*8(sp)
-        ld      s3, 6*8(sp)
-        ld      s4, 7*8(sp)
-        ld      s5, 8*8(sp)
-        ld      s6, 9*8(sp)
-        ld      s7, 10*8(sp)
-        ld      s8, 11*8(sp)
-        ld      s9, 12*8(sp)
-        ld      s10, 13*8(sp)
-        ld      s11, 14*8(sp)", 
-        "addi   sp, sp, 15*8", // sp:内核栈顶
-        "jr     ra", // 其实就是ret
-        options(noreturn)
-    )
+    asm!(concat!( // sscratch:用户上下文
+        "csrr   sp, sscratch\n", // sp:用户上下文
+        load!(sp, 33, sp), // sp:内核栈
+        load!(ra, 0, sp),
+        load!(gp, 1, sp),
+        load!(tp, 2, sp),
+        load!(s0, 3, sp),
+        load!(s1, 4, sp),
+        load!(s2, 5, sp),
+        load!(s3, 6, sp),
+        load!(s4, 7, sp),
+        load!(s5, 8, sp),
+        load!(s6, 9, sp),
+        load!(s7, 10, sp),
+        load!(s8, 11, sp),
+        load!(s9, 12, sp),
+        load!(s10, 13, sp),
+        load!(s11, 14, sp),
+        "addi   sp, sp, 15*", xlenb!(), "\n", // sp:内核栈顶
+        "jr     ra\n", // 其实就是ret
+    ), options(noreturn))
 }
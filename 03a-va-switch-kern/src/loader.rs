@@ -14,22 +14,21 @@ pub struct AppLoader<'a> {
 
 impl<'a> AppLoader<'a> {
     pub fn new() -> AppLoader<'a> {
-        extern "C" { fn _app_meta(); }
+        extern "C" { fn _app_meta(); fn _app_names(); }
         let num_app_ptr = _app_meta as usize as *const usize;
         let num_app = unsafe { num_app_ptr.read_volatile() };
+        let app_start = unsafe { core::slice::from_raw_parts(num_app_ptr.offset(1), num_app + 1) };
         let mut apps = Vec::with_capacity(num_app);
-        let mut cur = unsafe { num_app_ptr.offset(1) };
-        for _ in 0..num_app {
+        let word = core::mem::size_of::<usize>();
+        let mut cur = _app_names as usize as *const usize;
+        for i in 0..num_app {
             let name_len = unsafe { cur.read_volatile() };
             unsafe { cur = cur.offset(1) };
             let name_slice = unsafe { core::slice::from_raw_parts(cur as *const u8, name_len) };
             let name = alloc::str::from_utf8(name_slice).unwrap();
-            unsafe { cur = (cur as *const u8).offset(name_len as isize) as *const usize };
-            let start = unsafe { cur.read_volatile() }; 
-            unsafe { cur = cur.offset(1) };
-            let end = unsafe { cur.read_volatile() }; 
-            unsafe { cur = cur.offset(1) };
-            let elf_file = unsafe { core::slice::from_raw_parts(start as *const u8, end - start) };
+            // 名称之后按字对齐
+            unsafe { cur = (cur as *const u8).add((name_len + word - 1) / word * word) as *const usize };
+            let elf_file = unsafe { core::slice::from_raw_parts(app_start[i] as *const u8, app_start[i + 1] - app_start[i]) };
             apps.push(App { name, elf_file });
         }
         AppLoader { apps }
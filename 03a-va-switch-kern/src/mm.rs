@@ -1,23 +1,44 @@
 //! 虚拟内存模块
 
+#[cfg(not(test))]
 use alloc::alloc::Layout;
-use buddy_system_allocator::LockedHeap;
+#[cfg(not(test))]
+use buddy_system_allocator::{Heap, LockedHeapWithRescue};
+#[cfg(not(test))]
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::ops::Range;
 
+// 在主机上测试时，使用主机的堆分配器，内核的堆和下面直接读写寄存器的函数都不参与编译
+#[cfg(not(test))]
 const KERNEL_HEAP_SIZE: usize = 64 * 1024;
 
+#[cfg(not(test))]
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
-// 全局的堆分配器
+// 全局的堆分配器。一开始只有静态的HEAP_SPACE，用完以后由heap_rescue从页帧分配器得到更多的页帧
+#[cfg(not(test))]
 #[global_allocator]
-static HEAP: LockedHeap<32> = LockedHeap::empty();
+static HEAP: LockedHeapWithRescue<32> = LockedHeapWithRescue::new(heap_rescue);
 
-#[cfg_attr(not(test), alloc_error_handler)]
-#[allow(unused)]
+// 堆增长时使用的页帧分配器，建立页帧分配器以后由heap_grow_from设置
+#[cfg(not(test))]
+static HEAP_FRAME_ALLOC: spin::Once<&'static DefaultFrameAllocator> = spin::Once::new();
+
+// 堆从页帧分配器得到的页帧数。这些页帧不会还给页帧分配器
+#[cfg(not(test))]
+static HEAP_GROWN_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+// 每次增长至少得到的页帧数，避免频繁增长
+#[cfg(not(test))]
+const HEAP_GROW_MIN_FRAMES: usize = 16;
+
+#[cfg(not(test))]
+#[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
-    panic!("alloc error for layout {:?}", layout)
+    panic!("alloc error for layout {:?}, heap stats: {:?}", layout, heap_stats())
 }
 
+#[cfg(not(test))]
 pub(crate) fn heap_init() {
     unsafe {
         HEAP.lock().init(
@@ -31,41 +52,137 @@ pub(crate) fn heap_init() {
     println!("[kernel] Alloc test: {:?}", vec);
 }
 
+// 允许堆在空间不够时，从frame_alloc得到更多的页帧
+#[cfg(not(test))]
+pub(crate) fn heap_grow_from(frame_alloc: &'static DefaultFrameAllocator) {
+    HEAP_FRAME_ALLOC.call_once(|| frame_alloc);
+}
+
+// 堆分配失败时调用，此时已经持有堆的锁。伙伴系统只能从按大小对齐的块中分配，
+// 因此申请按分配大小对齐的连续页帧。页帧已经在物理内存的线性映射中，直接把它的虚拟地址加入堆中
+#[cfg(not(test))]
+fn heap_rescue(heap: &mut Heap<32>, layout: &Layout) {
+    let frame_alloc = match HEAP_FRAME_ALLOC.get() {
+        Some(frame_alloc) => frame_alloc,
+        None => return,
+    };
+    let frame_size = 1 << DefaultPageMode::FRAME_SIZE_BITS;
+    let block_frames = (usize::max(layout.size().next_power_of_two(), layout.align()) + frame_size - 1) / frame_size;
+    let n = usize::max(block_frames, HEAP_GROW_MIN_FRAMES);
+    let layout = match FrameLayout::new(block_frames) {
+        Ok(layout) => layout,
+        Err(_) => return,
+    };
+    // 页帧分配器的锁可能正被同一个核持有，比如它自己的数据结构正在分配内存；这时不能等待，只能放弃增长
+    let ppn = match frame_alloc.try_lock() {
+        Some(mut frames) => frames.allocate_frames(n, layout),
+        None => return,
+    };
+    if let Ok(ppn) = ppn {
+        let start = ppn.addr_begin::<DefaultPageMode>().to_virt().0;
+        unsafe { heap.add_to_heap(start, start + n * frame_size) };
+        HEAP_GROWN_FRAMES.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+// 内核堆的使用情况
+#[cfg(not(test))]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HeapStats {
+    // 堆的总大小，包括增长得到的部分
+    pub total_bytes: usize,
+    // 用户申请的字节数
+    pub user_bytes: usize,
+    // 实际分配的字节数，伙伴系统按2的幂分配，通常比申请的多
+    pub actual_bytes: usize,
+    // 从页帧分配器得到的页帧数
+    pub grown_frames: usize,
+}
+
+#[cfg(not(test))]
+pub fn heap_stats() -> HeapStats {
+    let heap = HEAP.lock();
+    HeapStats {
+        total_bytes: heap.stats_total_bytes(),
+        user_bytes: heap.stats_alloc_user(),
+        actual_bytes: heap.stats_alloc_actual(),
+        grown_frames: HEAP_GROWN_FRAMES.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(not(test))]
+#[cfg(feature = "self-test")]
+pub(crate) fn test_heap_grow() {
+    let before = heap_stats();
+    let vec = alloc::vec![0u8; KERNEL_HEAP_SIZE * 2]; // 比一开始整个堆还大
+    assert!(vec.iter().all(|&b| b == 0), "memory from grown heap");
+    let after = heap_stats();
+    assert!(after.total_bytes > before.total_bytes, "heap grown");
+    assert!(after.grown_frames >= vec.len() / 0x1000, "frames taken from frame allocator");
+    drop(vec);
+    assert_eq!(heap_stats().user_bytes, before.user_bytes, "memory returned to grown heap");
+    println!("[kernel-heap-test] Heap grow test passed, {:?}", heap_stats());
+}
+
+// 物理内存的线性映射：虚拟地址等于物理地址加上这个偏移量。内核也链接到线性映射的高地址上运行，
+// 低地址的一半留给用户。需要和链接脚本、启动代码中的偏移量保持一致
+#[cfg(target_pointer_width = "64")]
+pub const PHYS_VIRT_OFFSET: usize = 0xffff_ffff_0000_0000;
+#[cfg(target_pointer_width = "32")]
+pub const PHYS_VIRT_OFFSET: usize = 0x4000_0000;
+
+// 内核所在的物理内存从这里开始，启动页表和探测分页模式时都用大页映射这里。物理内存的大小从设备树中得到
+pub const MEMORY_START: usize = 0x8000_0000;
+
+// 线性映射能够覆盖的物理地址上限，需要和启动页表映射的范围一致；超过这个地址的物理内存暂时不使用
+#[cfg(all(target_pointer_width = "64", not(test)))]
+pub const MEMORY_LIMIT: usize = 0x1_0000_0000;
+#[cfg(all(target_pointer_width = "32", not(test)))]
+pub const MEMORY_LIMIT: usize = 0xbfff_f000; // 再往上一页的结束地址会越过4G
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct PhysAddr(pub usize);
 
 impl PhysAddr {
+    // 得到物理地址在线性映射中的虚拟地址
+    pub fn to_virt(&self) -> VirtAddr {
+        VirtAddr(self.0.wrapping_add(PHYS_VIRT_OFFSET))
+    }
     pub fn page_number<M: PageMode>(&self) -> PhysPageNum { 
         PhysPageNum(self.0 >> M::FRAME_SIZE_BITS)
     }
-    // pub fn page_offset(&self) -> usize { 
-    //     self.0 & (PAGE_SIZE - 1)
-    // }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct VirtAddr(pub usize);
 
 impl VirtAddr {
+    // 得到线性映射中的虚拟地址对应的物理地址。只对线性映射中的地址有效，比如内核的代码和数据
+    #[cfg(not(test))]
+    pub fn to_phys(&self) -> PhysAddr {
+        PhysAddr(self.0.wrapping_sub(PHYS_VIRT_OFFSET))
+    }
     pub fn page_number<M: PageMode>(&self) -> VirtPageNum { 
         VirtPageNum(self.0 >> M::FRAME_SIZE_BITS)
     }
-//     pub fn page_offset(&self) -> usize { 
-//         self.0 & (PAGE_SIZE - 1)
-    // }
+    pub fn page_offset<M: PageMode>(&self) -> usize { 
+        self.0 & ((1 << M::FRAME_SIZE_BITS) - 1)
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct PhysPageNum(usize);
 
 impl PhysPageNum {
     pub fn addr_begin<M: PageMode>(&self) -> PhysAddr {
         PhysAddr(self.0 << M::FRAME_SIZE_BITS)
     }
+    #[cfg(any(test, feature = "self-test"))]
     pub fn next_page(&self) -> PhysPageNum {
         // PhysPageNum不处理具体架构的PPN_BITS，它的合法性由具体架构保证
         PhysPageNum(self.0.wrapping_add(1))
     }
+    #[cfg(any(test, feature = "self-test"))]
     pub fn is_within_range(&self, begin: PhysPageNum, end: PhysPageNum) -> bool {
         if begin.0 <= end.0 {
             begin.0 <= self.0 && self.0 < end.0
@@ -75,28 +192,34 @@ impl PhysPageNum {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct VirtPageNum(usize);
 
-// impl VirtPageNum {
-//     pub fn addr_begin(&self) -> VirtAddr {
-//         VirtAddr(self.0 << FRAME_SIZE_BITS)
-//     }
-// }
+impl VirtPageNum {
+    pub fn addr_begin<M: PageMode>(&self) -> VirtAddr {
+        VirtAddr(self.0 << M::FRAME_SIZE_BITS)
+    }
+}
 
 use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
 
 // 页帧分配器。**对于物理空间的一个片段，只存在一个页帧分配器，无论有多少个处理核**
+// 内核使用BitmapFrameAllocator，这个分配器只在测试中作为对照
+#[cfg(any(test, feature = "self-test"))]
 #[derive(Debug)]
 pub struct StackFrameAllocator {
     current: PhysPageNum,
     end: PhysPageNum,
     recycled: Vec<PhysPageNum>,
+    // 被共享的页帧，以及除了第一个所有者以外，额外的引用次数。大部分页帧不会被共享，所以不需要记录
+    shared: BTreeMap<PhysPageNum, usize>,
 }
 
+#[cfg(any(test, feature = "self-test"))]
 impl StackFrameAllocator {
     pub fn new(start: PhysPageNum, end: PhysPageNum) -> Self {
-        StackFrameAllocator { current: start, end, recycled: Vec::new() }
+        StackFrameAllocator { current: start, end, recycled: Vec::new(), shared: BTreeMap::new() }
     }
     pub fn allocate_frame(&mut self) -> Result<PhysPageNum, FrameAllocError> {
         if let Some(ppn) = self.recycled.pop() {
@@ -111,14 +234,203 @@ impl StackFrameAllocator {
             }
         }
     }
+    // 分配n个连续的页帧，第一个页帧按layout对齐。回收栈中的页帧不一定连续，只从还没有分配过的区间中分配
+    pub fn allocate_frames(&mut self, n: usize, layout: FrameLayout) -> Result<PhysPageNum, FrameAllocError> {
+        let align = layout.frame_align();
+        let start = (self.current.0 + align - 1) / align * align;
+        if start < self.current.0 || start.checked_add(n).map(|end| end > self.end.0).unwrap_or(true) {
+            return Err(FrameAllocError)
+        }
+        // 为了对齐而跳过的页帧放进回收栈，以后还能分配
+        while self.current.0 != start {
+            self.recycled.push(self.current);
+            self.current = self.current.next_page();
+        }
+        self.current = PhysPageNum(start + n);
+        Ok(PhysPageNum(start))
+    }
+    // 减少页帧的一次引用；如果这是最后一次引用，回收页帧
     pub fn deallocate_frame(&mut self, ppn: PhysPageNum) {
         // validity check
-        if ppn.is_within_range(self.current, self.end) || self.recycled.iter().find(|&v| {*v == ppn}).is_some() {
-            panic!("Frame ppn={:x?} has not been allocated!", ppn);
+        self.check_allocated(ppn);
+        // shared frame, drop one reference
+        if let Some(count) = self.shared.get_mut(&ppn) {
+            *count -= 1;
+            if *count == 0 {
+                self.shared.remove(&ppn);
+            }
+            return
         }
         // recycle
         self.recycled.push(ppn);
     }
+    // 增加页帧的一次引用，用于在多个地址空间之间共享页帧
+    pub fn share_frame(&mut self, ppn: PhysPageNum) {
+        self.check_allocated(ppn);
+        *self.shared.entry(ppn).or_insert(0) += 1;
+    }
+    // 页帧被引用的次数
+    pub fn frame_ref_count(&self, ppn: PhysPageNum) -> usize {
+        self.check_allocated(ppn);
+        self.shared.get(&ppn).map(|count| count + 1).unwrap_or(1)
+    }
+    fn check_allocated(&self, ppn: PhysPageNum) {
+        if ppn.is_within_range(self.current, self.end) || self.recycled.iter().find(|&v| {*v == ppn}).is_some() {
+            panic!("Frame ppn={:x?} has not been allocated!", ppn);
+        }
+    }
+}
+
+// 位图页帧分配器。每个页帧用一个位表示是否已经分配，可以分配连续的、满足对齐要求的多个页帧
+#[derive(Debug)]
+pub struct BitmapFrameAllocator {
+    start: PhysPageNum,
+    len: usize,
+    bitmap: Vec<u64>, // 位为1表示页帧已经分配
+    free: usize,
+    // 被共享的页帧，以及除了第一个所有者以外，额外的引用次数
+    shared: BTreeMap<PhysPageNum, usize>,
+}
+
+impl BitmapFrameAllocator {
+    pub fn new(start: PhysPageNum, end: PhysPageNum) -> Self {
+        let len = end.0 - start.0;
+        let mut bitmap = alloc::vec![0u64; (len + 63) / 64];
+        // 最后一个字中超出范围的位当作已经分配，这样查找时不会分配它们
+        if len % 64 != 0 {
+            *bitmap.last_mut().unwrap() = !0 << (len % 64);
+        }
+        BitmapFrameAllocator { start, len, bitmap, free: len, shared: BTreeMap::new() }
+    }
+    pub fn allocate_frame(&mut self) -> Result<PhysPageNum, FrameAllocError> {
+        for (i, word) in self.bitmap.iter_mut().enumerate() {
+            if *word != !0 {
+                let bit = (!*word).trailing_zeros() as usize;
+                *word |= 1 << bit;
+                self.free -= 1;
+                return Ok(PhysPageNum(self.start.0 + i * 64 + bit))
+            }
+        }
+        Err(FrameAllocError)
+    }
+    // 分配n个连续的页帧，第一个页帧的物理页号按layout对齐
+    pub fn allocate_frames(&mut self, n: usize, layout: FrameLayout) -> Result<PhysPageNum, FrameAllocError> {
+        let align = layout.frame_align();
+        // 第一个对齐的页帧在位图中的下标
+        let mut idx = (align - self.start.0 % align) % align;
+        while idx + n <= self.len {
+            match self.find_allocated(idx..idx + n) {
+                None => {
+                    for i in idx..idx + n {
+                        self.bitmap[i / 64] |= 1 << (i % 64);
+                    }
+                    self.free -= n;
+                    return Ok(PhysPageNum(self.start.0 + idx))
+                },
+                // 区间中有已经分配的页帧，从它之后的下一个对齐的位置开始找
+                Some(allocated) => idx = (self.start.0 + allocated + align) / align * align - self.start.0,
+            }
+        }
+        Err(FrameAllocError)
+    }
+    // 减少页帧的一次引用；如果这是最后一次引用，回收页帧
+    pub fn deallocate_frame(&mut self, ppn: PhysPageNum) {
+        let idx = self.check_allocated(ppn);
+        if let Some(count) = self.shared.get_mut(&ppn) {
+            *count -= 1;
+            if *count == 0 {
+                self.shared.remove(&ppn);
+            }
+            return
+        }
+        self.bitmap[idx / 64] &= !(1 << (idx % 64));
+        self.free += 1;
+    }
+    // 增加页帧的一次引用，用于在多个地址空间之间共享页帧
+    pub fn share_frame(&mut self, ppn: PhysPageNum) {
+        self.check_allocated(ppn);
+        *self.shared.entry(ppn).or_insert(0) += 1;
+    }
+    // 页帧被引用的次数
+    pub fn frame_ref_count(&self, ppn: PhysPageNum) -> usize {
+        self.check_allocated(ppn);
+        self.shared.get(&ppn).map(|count| count + 1).unwrap_or(1)
+    }
+    // 把区间中还没有分配的页帧标记为已经分配，以后不会再分配出去；用于避开设备树等保留的物理内存。
+    // 区间超出分配器范围的部分被忽略
+    pub fn reserve(&mut self, start: PhysPageNum, end: PhysPageNum) {
+        let from = usize::min(start.0.saturating_sub(self.start.0), self.len);
+        let to = usize::min(end.0.saturating_sub(self.start.0), self.len);
+        for idx in from..to {
+            if !self.is_allocated(idx) {
+                self.bitmap[idx / 64] |= 1 << (idx % 64);
+                self.free -= 1;
+            }
+        }
+    }
+    // 统计空闲页帧的数量和碎片情况
+    pub fn stats(&self) -> FrameAllocStats {
+        let mut ans = FrameAllocStats { total_frames: self.len, free_frames: self.free, free_extents: 0, largest_free_extent: 0 };
+        let mut extent = 0;
+        for idx in 0..self.len {
+            if self.is_allocated(idx) {
+                extent = 0;
+                continue;
+            }
+            if extent == 0 {
+                ans.free_extents += 1;
+            }
+            extent += 1;
+            ans.largest_free_extent = usize::max(ans.largest_free_extent, extent);
+        }
+        ans
+    }
+    // 找到区间中第一个已经分配的页帧的下标
+    fn find_allocated(&self, range: Range<usize>) -> Option<usize> {
+        let mut idx = range.start;
+        while idx < range.end {
+            if idx % 64 == 0 && idx + 64 <= range.end && self.bitmap[idx / 64] == 0 {
+                idx += 64; // 整个字都是空闲的
+                continue;
+            }
+            if self.is_allocated(idx) {
+                return Some(idx)
+            }
+            idx += 1;
+        }
+        None
+    }
+    fn is_allocated(&self, idx: usize) -> bool {
+        self.bitmap[idx / 64] & (1 << (idx % 64)) != 0
+    }
+    fn check_allocated(&self, ppn: PhysPageNum) -> usize {
+        let idx = ppn.0.wrapping_sub(self.start.0);
+        if idx >= self.len || !self.is_allocated(idx) {
+            panic!("Frame ppn={:x?} has not been allocated!", ppn);
+        }
+        idx
+    }
+}
+
+// 页帧分配器的统计信息
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameAllocStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    // 空闲页帧组成的连续区间数量
+    pub free_extents: usize,
+    // 最长的连续空闲区间包含的页帧数
+    pub largest_free_extent: usize,
+}
+
+impl FrameAllocStats {
+    // 外部碎片的比例，以百分数表示。为0说明所有空闲页帧都是连续的
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_frames == 0 {
+            return 0
+        }
+        100 - self.largest_free_extent * 100 / self.free_frames
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -133,6 +445,14 @@ pub struct FrameLayout {
 
 // 应当从PageMode::get_layout_for_level中获得
 impl FrameLayout {
+    // 对齐到的页帧数必须是2的幂
+    pub const fn new(frame_align: usize) -> Result<Self, FrameLayoutError> {
+        if frame_align.is_power_of_two() {
+            Ok(Self { frame_align })
+        } else {
+            Err(FrameLayoutError)
+        }
+    }
     // 未检查参数，用于实现PageMode
     pub const unsafe fn new_unchecked(frame_align: usize) -> Self {
         Self { frame_align }
@@ -145,6 +465,7 @@ impl FrameLayout {
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct FrameLayoutError;
 
+#[cfg(any(test, feature = "self-test"))]
 pub(crate) fn test_frame_alloc() {
     let from = PhysPageNum(0x80000);
     let to = PhysPageNum(0x100000);
@@ -156,9 +477,55 @@ pub(crate) fn test_frame_alloc() {
     alloc.deallocate_frame(f1.unwrap());
     let f3 = alloc.allocate_frame();
     assert_eq!(f3, Ok(PhysPageNum(0x80000)), "after free first, third allocation");
+    alloc.share_frame(f2.unwrap());
+    assert_eq!(alloc.frame_ref_count(f2.unwrap()), 2, "shared frame");
+    alloc.deallocate_frame(f2.unwrap());
+    assert_eq!(alloc.frame_ref_count(f2.unwrap()), 1, "free one reference of shared frame");
+    alloc.deallocate_frame(f2.unwrap());
+    let f4 = alloc.allocate_frame();
+    assert_eq!(f4, Ok(PhysPageNum(0x80001)), "after free shared frame, fourth allocation");
+    let f5 = alloc.allocate_frames(4, unsafe { FrameLayout::new_unchecked(512) });
+    assert_eq!(f5, Ok(PhysPageNum(0x80200)), "aligned contiguous allocation");
+    let f6 = alloc.allocate_frame();
+    assert_eq!(f6, Ok(PhysPageNum(0x801ff)), "allocate frame skipped by alignment");
     println!("[kernel-frame-test] Frame allocator test passed");
 }
 
+#[cfg(any(test, feature = "self-test"))]
+pub(crate) fn test_bitmap_frame_alloc() {
+    let from = PhysPageNum(0x80001);
+    let to = PhysPageNum(0x80801);
+    let mut alloc = BitmapFrameAllocator::new(from, to);
+    let f1 = alloc.allocate_frame();
+    assert_eq!(f1, Ok(PhysPageNum(0x80001)), "first allocation");
+    assert_eq!(FrameLayout::new(3), Err(FrameLayoutError), "frame align not power of two");
+    let f2 = alloc.allocate_frames(3, FrameLayout::new(4).unwrap());
+    assert_eq!(f2, Ok(PhysPageNum(0x80004)), "aligned contiguous allocation");
+    let f3 = alloc.allocate_frame();
+    assert_eq!(f3, Ok(PhysPageNum(0x80002)), "allocate frame before aligned area");
+    let f4 = alloc.allocate_frames(512, unsafe { FrameLayout::new_unchecked(512) });
+    assert_eq!(f4, Ok(PhysPageNum(0x80200)), "allocate frames for a 2M huge page");
+    let stats = alloc.stats();
+    assert_eq!((stats.total_frames, stats.free_frames), (0x800, 0x800 - 517), "free frames");
+    assert_eq!((stats.free_extents, stats.largest_free_extent), (3, 0x401), "free extents");
+    assert_eq!(stats.fragmentation_percent(), 34, "fragmentation");
+    alloc.deallocate_frame(PhysPageNum(0x80005));
+    alloc.deallocate_frame(f3.unwrap());
+    assert_eq!(alloc.stats().free_extents, 4, "free extents after deallocation");
+    alloc.share_frame(f1.unwrap());
+    alloc.deallocate_frame(f1.unwrap());
+    assert_eq!(alloc.frame_ref_count(f1.unwrap()), 1, "free one reference of shared frame");
+    alloc.deallocate_frame(f1.unwrap());
+    let f5 = alloc.allocate_frames(2, unsafe { FrameLayout::new_unchecked(1) });
+    assert_eq!(f5, Ok(PhysPageNum(0x80001)), "contiguous allocation fills the hole");
+    assert_eq!(alloc.allocate_frames(0x800, unsafe { FrameLayout::new_unchecked(1) }), Err(FrameAllocError), "allocate too many frames");
+    let free = alloc.stats().free_frames;
+    alloc.reserve(PhysPageNum(0x80000), PhysPageNum(0x80010)); // 一部分在范围之外，一部分已经分配
+    assert_eq!(alloc.stats().free_frames, free - 11, "reserve free frames in range");
+    assert_eq!(alloc.allocate_frame(), Ok(PhysPageNum(0x80010)), "reserved frames not allocated");
+    println!("[kernel-frame-test] Bitmap frame allocator test passed, {:?}", alloc.stats());
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct AddressSpaceId(u16);
 
@@ -175,6 +542,7 @@ impl AddressSpaceId {
 const DEFAULT_ASID: AddressSpaceId = AddressSpaceId(0); // RISC-V架构规定，必须实现
 
 // 每个平台上是不一样的，需要通过读写satp寄存器获得
+#[cfg(not(test))]
 pub fn max_asid() -> AddressSpaceId {
     #[cfg(target_pointer_width = "64")]
     let mut val: usize = ((1 << 16) - 1) << 44;
@@ -211,7 +579,7 @@ pub struct StackAsidAllocator {
 pub struct AsidAllocError;
 
 impl StackAsidAllocator {
-    pub fn new(max_asid: AddressSpaceId) -> Self {
+    pub const fn new(max_asid: AddressSpaceId) -> Self {
         StackAsidAllocator { current: DEFAULT_ASID, exhausted: false, max: max_asid, recycled: Vec::new() }
     }
 
@@ -235,14 +603,113 @@ impl StackAsidAllocator {
         }
     }
     
-    fn deallocate_asid(&mut self, asid: AddressSpaceId) {
-        if asid.next_asid(self.max).is_none() || self.recycled.iter().find(|&v| {*v == asid}).is_some() {
+    pub fn deallocate_asid(&mut self, asid: AddressSpaceId) {
+        // 已经分配的编号小于current；编号用完时，最大的编号也已经分配出去
+        let allocated = asid.0 < self.current.0 || (self.exhausted && asid == self.max);
+        if !allocated || self.recycled.iter().find(|&v| {*v == asid}).is_some() {
             panic!("Asid {:x?} has not been allocated!", asid);
         }
         self.recycled.push(asid);
     }
 }
 
+// 带有代数的地址空间编号
+//
+// 地址空间编号用完时，代数加一，并刷新所有的页表缓存；之前代数分配的编号全部作废，
+// 它们对应的地址空间下一次切换时需要重新分配编号。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AsidTag {
+    asid: AddressSpaceId,
+    generation: usize,
+}
+
+impl AsidTag {
+    pub fn asid(&self) -> AddressSpaceId {
+        self.asid
+    }
+}
+
+// 按代数管理地址空间编号，编号用完后回绕，从而可以运行比编号数量更多的地址空间
+//
+// 硬件不支持地址空间编号时，最大编号为0，每次切换到不同的地址空间都会回绕一次，也就是每次切换都刷新页表缓存。
+#[derive(Debug)]
+pub struct AsidManager {
+    generation: usize,
+    max: AddressSpaceId,
+    alloc: StackAsidAllocator, // 当前代数的编号分配器
+}
+
+impl AsidManager {
+    pub const fn new(max_asid: AddressSpaceId) -> Self {
+        AsidManager { generation: 0, max: max_asid, alloc: StackAsidAllocator::new(max_asid) }
+    }
+    // 设置平台支持的最大编号，开始新的一代。应当在切换到任何地址空间之前调用
+    pub fn set_max_asid(&mut self, max_asid: AddressSpaceId) {
+        self.max = max_asid;
+        self.new_generation();
+    }
+    // 切换到地址空间时，得到它在当前代数下的编号。tag是地址空间上一次得到的编号，如果已经作废，重新分配。
+    // 返回的布尔值表示是否回绕到了新的一代，此时需要刷新所有的页表缓存
+    pub fn activate(&mut self, tag: Option<AsidTag>) -> (AsidTag, bool) {
+        if let Some(tag) = tag {
+            if tag.generation == self.generation {
+                return (tag, false)
+            }
+        }
+        if let Ok(asid) = self.alloc.allocate_asid() {
+            return (AsidTag { asid, generation: self.generation }, false)
+        }
+        self.new_generation();
+        let asid = self.alloc.allocate_asid().expect("allocate asid in new generation");
+        (AsidTag { asid, generation: self.generation }, true)
+    }
+    // 地址空间被释放时，回收它的编号；已经作废的编号不需要回收。
+    // 返回的布尔值表示编号是否被回收，此时需要刷新这个编号的页表缓存，以便分配给其它地址空间
+    pub fn deallocate(&mut self, tag: AsidTag) -> bool {
+        if tag.generation != self.generation {
+            return false
+        }
+        self.alloc.deallocate_asid(tag.asid);
+        true
+    }
+    fn new_generation(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.alloc = StackAsidAllocator::new(self.max);
+    }
+}
+
+// 内核目前只在一个处理核上运行，因此只有一个地址空间编号管理器
+pub static ASID_MANAGER: spin::Mutex<AsidManager> = spin::Mutex::new(AsidManager::new(DEFAULT_ASID));
+
+#[cfg(any(test, feature = "self-test"))]
+pub(crate) fn test_asid_manager() {
+    let mut manager = AsidManager::new(AddressSpaceId(2));
+    let (t1, flush) = manager.activate(None);
+    assert_eq!((t1.asid(), flush), (AddressSpaceId(0), false), "first activation");
+    assert_eq!(manager.activate(Some(t1)), (t1, false), "activate again in same generation");
+    let (t2, _) = manager.activate(None);
+    let (t3, _) = manager.activate(None);
+    assert_eq!((t2.asid(), t3.asid()), (AddressSpaceId(1), AddressSpaceId(2)), "allocate all asids");
+    assert!(manager.deallocate(t2), "deallocate asid in current generation");
+    let (t4, flush) = manager.activate(None);
+    assert_eq!((t4.asid(), flush), (AddressSpaceId(1), false), "reuse deallocated asid");
+    let (t5, flush) = manager.activate(None);
+    assert_eq!((t5.asid(), flush), (AddressSpaceId(0), true), "rollover when asid exhausted");
+    let (t1_new, flush) = manager.activate(Some(t1));
+    assert_eq!((t1_new.asid(), flush), (AddressSpaceId(1), false), "reallocate asid from old generation");
+    assert!(!manager.deallocate(t3), "deallocate asid from old generation");
+
+    let mut manager = AsidManager::new(DEFAULT_ASID); // asid not implemented
+    let (t1, _) = manager.activate(None);
+    assert_eq!(manager.activate(Some(t1)), (t1, false), "asid not implemented, activate again");
+    let (t2, flush) = manager.activate(None);
+    assert_eq!((t2.asid(), flush), (DEFAULT_ASID, true), "asid not implemented, switch to another");
+    let (_, flush) = manager.activate(Some(t1));
+    assert!(flush, "asid not implemented, switch back");
+    println!("[kernel-asid-test] Asid manager test passed");
+}
+
+#[cfg(any(test, feature = "self-test"))]
 pub(crate) fn test_asid_alloc() {
     let max_asid = AddressSpaceId(0xffff);
     let mut alloc = StackAsidAllocator::new(max_asid);
@@ -275,20 +742,79 @@ pub(crate) fn test_asid_alloc() {
     println!("[kernel-asid-test] Asid allocator test passed");
 }
 
-pub trait FrameAllocator {
+// 访问物理内存的方式。内核经过物理内存的线性映射访问页帧；在主机上测试时，可以用一块普通的内存模拟物理内存
+pub trait PhysMemory {
+    // 得到物理地址在当前地址空间中可以访问的虚拟地址
+    fn phys_to_virt(&self, pa: PhysAddr) -> VirtAddr;
+}
+
+// 页帧分配器需要说明如何访问它分配的页帧，以便在页帧中填写页表
+pub trait FrameAllocator: PhysMemory {
     fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError>;
+    // 减少页帧的一次引用；最后一次引用被释放时，页帧被回收
     fn deallocate_frame(&self, ppn: PhysPageNum);
+    // 增加页帧的一次引用
+    fn share_frame(&self, ppn: PhysPageNum);
+    // 页帧被引用的次数
+    fn frame_ref_count(&self, ppn: PhysPageNum) -> usize;
+}
+
+pub type DefaultFrameAllocator = spin::Mutex<BitmapFrameAllocator>;
+
+// 页帧分配器和内核堆换算物理页号和地址时使用的分页模式；所有模式的页帧都是4K，和内核实际使用的模式无关
+#[cfg(target_pointer_width = "64")]
+pub type DefaultPageMode = Sv39;
+#[cfg(target_pointer_width = "32")]
+pub type DefaultPageMode = Sv32;
+
+#[cfg(any(test, feature = "self-test"))]
+impl PhysMemory for spin::Mutex<StackFrameAllocator> {
+    fn phys_to_virt(&self, pa: PhysAddr) -> VirtAddr {
+        pa.to_virt()
+    }
+}
+
+#[cfg(any(test, feature = "self-test"))]
+impl FrameAllocator for spin::Mutex<StackFrameAllocator> {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.lock().allocate_frame()
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.lock().deallocate_frame(ppn)
+    }
+    fn share_frame(&self, ppn: PhysPageNum) {
+        self.lock().share_frame(ppn)
+    }
+    fn frame_ref_count(&self, ppn: PhysPageNum) -> usize {
+        self.lock().frame_ref_count(ppn)
+    }
 }
 
-pub type DefaultFrameAllocator = spin::Mutex<StackFrameAllocator>;
+impl PhysMemory for spin::Mutex<BitmapFrameAllocator> {
+    fn phys_to_virt(&self, pa: PhysAddr) -> VirtAddr {
+        pa.to_virt()
+    }
+}
 
-impl FrameAllocator for DefaultFrameAllocator {
+impl FrameAllocator for spin::Mutex<BitmapFrameAllocator> {
     fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
         self.lock().allocate_frame()
     }
     fn deallocate_frame(&self, ppn: PhysPageNum) {
         self.lock().deallocate_frame(ppn)
     }
+    fn share_frame(&self, ppn: PhysPageNum) {
+        self.lock().share_frame(ppn)
+    }
+    fn frame_ref_count(&self, ppn: PhysPageNum) -> usize {
+        self.lock().frame_ref_count(ppn)
+    }
+}
+
+impl<A: PhysMemory + ?Sized> PhysMemory for &A {
+    fn phys_to_virt(&self, pa: PhysAddr) -> VirtAddr {
+        (**self).phys_to_virt(pa)
+    }
 }
 
 impl<A: FrameAllocator + ?Sized> FrameAllocator for &A { 
@@ -298,6 +824,12 @@ impl<A: FrameAllocator + ?Sized> FrameAllocator for &A {
     fn deallocate_frame(&self, ppn: PhysPageNum) {
         (**self).deallocate_frame(ppn)
     }
+    fn share_frame(&self, ppn: PhysPageNum) {
+        (**self).share_frame(ppn)
+    }
+    fn frame_ref_count(&self, ppn: PhysPageNum) -> usize {
+        (**self).frame_ref_count(ppn)
+    }
 }
 
 // 表示整个页帧内存的所有权
@@ -320,6 +852,16 @@ impl<A: FrameAllocator> FrameBox<A> {
     //     Self { ppn, frame_alloc }
     // }
 
+    // 共享这个页帧，得到同一个页帧的另一个所有者；所有的所有者都释放后，页帧才会被回收
+    pub fn share(&self) -> FrameBox<A> where A: Clone {
+        self.frame_alloc.share_frame(self.ppn);
+        FrameBox { ppn: self.ppn, frame_alloc: self.frame_alloc.clone() }
+    }
+    // 这个页帧被多少个FrameBox共享
+    pub fn ref_count(&self) -> usize {
+        self.frame_alloc.frame_ref_count(self.ppn)
+    }
+
     fn phys_page_num(&self) -> PhysPageNum {
         self.ppn
     }
@@ -368,7 +910,7 @@ pub trait PageMode: Copy {
     // 解释页表项目；如果项目无效，返回None，可以直接操作slot写入其它数据
     fn slot_try_get_entry(slot: &mut Self::Slot) -> Result<&mut Self::Entry, &mut Self::Slot>;
     // 页表项的设置
-    type Flags : Clone;
+    type Flags : Clone + core::fmt::Debug;
     // 写数据，建立一个到子页表的页表项
     fn slot_set_child(slot: &mut Self::Slot, ppn: PhysPageNum);
     // 写数据，建立一个到内存地址的页表项
@@ -377,6 +919,18 @@ pub trait PageMode: Copy {
     fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags);
     // 得到一个页表项目包含的物理页号
     fn entry_get_ppn(entry: &mut Self::Entry) -> PhysPageNum;
+    // 得到一个页表项目的设置
+    fn entry_get_flags(entry: &mut Self::Entry) -> Self::Flags;
+    // 这个页表项目是否为叶子节点，即是否直接指向一段内存，而不是下一级页表
+    fn entry_is_leaf(entry: &mut Self::Entry) -> bool;
+    // 这个页表项目是否为全局映射；如果指向下一级页表，下一级页表中的所有映射都是全局映射
+    fn entry_is_global(entry: &mut Self::Entry) -> bool;
+    // 清除页表项目，使它成为无效的页表项
+    fn entry_clear(entry: &mut Self::Entry);
+    // 页表中的所有条目是否都是无效条目
+    fn page_table_is_empty(table: &Self::PageTable) -> bool;
+    // 页表项的设置是否同时可写和可执行
+    fn flags_is_write_execute(flags: &Self::Flags) -> bool;
 }
 
 // 我们认为今天的分页系统都是分为不同的等级，就是多级页表，这里表示页表的等级是多少
@@ -391,9 +945,11 @@ impl PageLevel {
 }
 
 // Sv39分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Sv39;
 
+#[cfg(target_pointer_width = "64")]
 impl PageMode for Sv39 {
     const FRAME_SIZE_BITS: usize = 12;
     const PPN_BITS: usize = 44;
@@ -436,12 +992,9 @@ impl PageMode for Sv39 {
     fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
         let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
         let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
-        if level.0 <= 1 {
-            let start_idx1 = vpn_range.start.0 >> ((level.0 + 1) * 9);
-            let end_idx1 = vpn_range.end.0 >> ((level.0 + 1) * 9);
-            if end_idx1 > start_idx1 {
-                end = 512;
-            }
+        // 区间跨过了上一级的边界时，本级的索引到页表的末尾为止；最高一级的区间可能到达地址空间的最顶端
+        if vpn_range.end.0 >> ((level.0 + 1) * 9) > vpn_range.start.0 >> ((level.0 + 1) * 9) {
+            end = 512;
         }
         start..end
     }
@@ -449,7 +1002,7 @@ impl PageMode for Sv39 {
         VirtPageNum(match level.0 {
             0 => (vpn.0 & !((1 << 9) - 1)) + idx,
             1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
-            2 => (vpn.0 & !((1 << 44) - 1)) + (idx << 18),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
             _ => unimplemented!("this level does not exist on Sv39"),
         })
     }
@@ -482,13 +1035,33 @@ impl PageMode for Sv39 {
     fn entry_get_ppn(entry: &mut Sv39PageEntry) -> PhysPageNum {
         entry.ppn()
     }
+    fn entry_get_flags(entry: &mut Sv39PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn entry_is_leaf(entry: &mut Sv39PageEntry) -> bool {
+        entry.flags().intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X)
+    }
+    fn entry_is_global(entry: &mut Sv39PageEntry) -> bool {
+        entry.flags().contains(Sv39Flags::G)
+    }
+    fn entry_clear(entry: &mut Sv39PageEntry) {
+        entry.bits = 0;
+    }
+    fn page_table_is_empty(table: &Sv39PageTable) -> bool {
+        table.entries.iter().all(|slot| slot.bits & Sv39Flags::V.bits() as usize == 0)
+    }
+    fn flags_is_write_execute(flags: &Sv39Flags) -> bool {
+        flags.contains(Sv39Flags::W | Sv39Flags::X)
+    }
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageTable {
     entries: [Sv39PageSlot; 512], // todo: other modes
 }
 
+#[cfg(target_pointer_width = "64")]
 impl core::ops::Index<usize> for Sv39PageTable {
     type Output = Sv39PageSlot;
     fn index(&self, idx: usize) -> &Sv39PageSlot {
@@ -496,17 +1069,20 @@ impl core::ops::Index<usize> for Sv39PageTable {
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 impl core::ops::IndexMut<usize> for Sv39PageTable {
     fn index_mut(&mut self, idx: usize) -> &mut Sv39PageSlot {
         &mut self.entries[idx]
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageSlot {
     bits: usize,
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageEntry {
     bits: usize,
@@ -514,6 +1090,7 @@ pub struct Sv39PageEntry {
 
 use bit_field::BitField;
 
+#[cfg(target_pointer_width = "64")]
 impl Sv39PageEntry {
     #[inline]
     pub fn ppn(&self) -> PhysPageNum {
@@ -521,7 +1098,7 @@ impl Sv39PageEntry {
     }
     #[inline]
     pub fn flags(&self) -> Sv39Flags {
-        Sv39Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
+        Sv39Flags::from_bits_truncate(self.bits.get_bits(0..10) as u16)
     }
     #[inline]
     pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
@@ -530,7 +1107,7 @@ impl Sv39PageEntry {
 }
 
 bitflags::bitflags! {
-    pub struct Sv39Flags: u8 {
+    pub struct Sv39Flags: u16 {
         const V = 1 << 0;
         const R = 1 << 1;
         const W = 1 << 2;
@@ -539,117 +1116,1506 @@ bitflags::bitflags! {
         const G = 1 << 5;
         const A = 1 << 6;
         const D = 1 << 7;
+        // 以下是留给软件使用的RSW位，硬件不会解释它们
+        const COW = 1 << 8; // 写时复制的页，写入它将产生缺页异常，由内核复制页帧
+        const SHARED = 1 << 9; // 共享内存的页，复制地址空间时仍然共享同一个页帧，不使用写时复制
     }
 }
 
-// 表示一个分页系统实现的地址空间
-//
-// 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
-#[derive(Debug)]
-pub struct PagedAddrSpace<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
-    root_frame: FrameBox<A>,
-    frames: Vec<FrameBox<A>>,
-    frame_alloc: A,
-    page_mode: M,
-}
+// Sv48和Sv57的页表项格式与Sv39相同，只是页表的等级更多
+#[cfg(target_pointer_width = "64")]
+pub type Sv48PageTable = Sv39PageTable;
+#[cfg(target_pointer_width = "64")]
+pub type Sv48PageSlot = Sv39PageSlot;
+#[cfg(target_pointer_width = "64")]
+pub type Sv48PageEntry = Sv39PageEntry;
+#[cfg(target_pointer_width = "64")]
+pub type Sv48Flags = Sv39Flags;
+
+#[cfg(target_pointer_width = "64")]
+pub type Sv57PageTable = Sv39PageTable;
+#[cfg(target_pointer_width = "64")]
+pub type Sv57PageSlot = Sv39PageSlot;
+#[cfg(target_pointer_width = "64")]
+pub type Sv57PageEntry = Sv39PageEntry;
+#[cfg(target_pointer_width = "64")]
+pub type Sv57Flags = Sv39Flags;
+
+// Sv48分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv48;
 
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
-    // 创建一个空的分页地址空间。一定会产生内存的写操作
-    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
-        // 新建一个满足根页表对齐要求的帧；虽然代码没有体现，通常对齐要求是1
-        let mut root_frame = FrameBox::try_new_in(frame_alloc.clone())?;
-        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
-        // 向帧里填入一个空的根页表 
-        unsafe { fill_frame_with_initialized_page_table::<A, M>(&mut root_frame) };
-        Ok(Self { root_frame, frames: Vec::new(), frame_alloc, page_mode })
+#[cfg(target_pointer_width = "64")]
+impl PageMode for Sv48 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 44;
+    type PageTable = Sv48PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(512), // 2M页
+            2 => FrameLayout::new_unchecked(512 * 512), // 1G页
+            3 => FrameLayout::new_unchecked(512 * 512 * 512), // 512G页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv48")
+        } }
     }
-    // 得到根页表的地址
-    pub fn root_page_number(&self) -> PhysPageNum {
-        self.root_frame.phys_page_num()
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(3), PageLevel(2), PageLevel(1)],
+            2 => &[PageLevel(3), PageLevel(2)],
+            3 => &[PageLevel(3)],
+            _ => unimplemented!("this level does not exist on Sv48"),
+        }
     }
-}
-
-#[inline] unsafe fn unref_ppn_mut<'a, M: PageMode>(ppn: PhysPageNum) -> &'a mut M::PageTable {
-    let pa = ppn.addr_begin::<M>();
-    &mut *(pa.0 as *mut M::PageTable)
-}
-
-#[inline] unsafe fn fill_frame_with_initialized_page_table<A: FrameAllocator, M: PageMode>(b: &mut FrameBox<A>) {
-    let a = &mut *(b.ppn.addr_begin::<M>().0 as *mut M::PageTable);
-    M::init_page_table(a);
-}
-
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {    
-    // 设置entry。如果寻找的过程中，中间的页表没创建，那么创建它们
-    unsafe fn alloc_get_table(&mut self, entry_level: PageLevel, vpn_start: VirtPageNum) -> Result<&mut M::PageTable, FrameAllocError> {
-        let mut ppn = self.root_frame.phys_page_num();
-        for &level in M::visit_levels_before(entry_level) {
-            // println!("[] BEFORE PPN = {:x?}", ppn);
-            let page_table = unref_ppn_mut::<M>(ppn);
-            let vidx = M::vpn_index(vpn_start, level);
-            match M::slot_try_get_entry(&mut page_table[vidx]) {
-                Ok(entry) => ppn = M::entry_get_ppn(entry),
-                Err(mut slot) => {  // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
-                    let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
-                    M::slot_set_child(&mut slot, frame_box.phys_page_num());
-                    // println!("[] Created a new frame box");
-                    ppn = frame_box.phys_page_num();
-                    self.frames.push(frame_box);
-                }
-            }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(3), PageLevel(2), PageLevel(1)],
+            1 => &[PageLevel(3), PageLevel(2)],
+            2 => &[PageLevel(3)],
+            3 => &[],
+            _ => unimplemented!("this level does not exist on Sv48"),
         }
-        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
-        let page_table = unref_ppn_mut::<M>(ppn); // 此时ppn是当前所需要修改的页表
-        // 创建了一个没有约束的生命周期。不过我们可以判断它是合法的，因为它的所有者是Self，在Self的周期内都合法
-        Ok(&mut *(page_table as *mut _))
     }
-    pub fn allocate_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
-        for (page_level, vpn_range) in MapPairs::solve(vpn, ppn, n, self.page_mode) {
-            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
-            let table = unsafe { self.alloc_get_table(page_level, vpn_range.start) }?;
-            let idx_range = M::vpn_index_range(vpn_range.clone(), page_level);
-            // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
-            for vidx in idx_range {
-                let this_ppn = PhysPageNum(ppn.0 - vpn.0 + M::vpn_level_index(vpn_range.start, page_level, vidx).0);
-                // println!("[kernel-alloc-map-test] Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
-                match M::slot_try_get_entry(&mut table[vidx]) {
-                    Ok(_entry) => panic!("already allocated"),
-                    Err(slot) => M::slot_set_mapping(slot, this_ppn, flags.clone())
-                }
-            }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            2 => &[PageLevel(2), PageLevel(1), PageLevel(0)],
+            3 => &[PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv48"),
         }
-        Ok(())
     }
-    // pub fn unmap(&mut self, vpn: VirtPageNum) {
-    //     todo!()
-    // }
-}
-
-#[derive(Debug)]
-pub struct MapPairs<M> {
-    ans_iter: alloc::vec::IntoIter<(PageLevel, Range<VirtPageNum>)>,
-    mode: M,
-}
-
-impl<M: PageMode> MapPairs<M> {
-    pub fn solve(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, mode: M) -> Self {
-        let mut ans = Vec::new();
-        for &i in M::visit_levels_until(PageLevel::leaf_level()) {
-            let align = M::get_layout_for_level(i).frame_align();
-            if (vpn.0 - ppn.0) % align != 0 || n < align {
-                continue;
-            }
-            let (mut ve_prev, mut vs_prev) = (None, None);
-            for &j in M::visit_levels_from(i) {
-                let align_cur = M::get_layout_for_level(j).frame_align();
-                let ve_cur = align_cur * ((vpn.0 + align_cur - 1) / align_cur); // a * roundup(v / a)
-                let vs_cur = align_cur * ((vpn.0 + n) / align_cur); // a * rounddown((v+n) / a)
-                if let (Some(ve_prev), Some(vs_prev)) = (ve_prev, vs_prev) {
-                    if ve_cur != ve_prev {
-                        ans.push((j, VirtPageNum(ve_cur)..VirtPageNum(ve_prev)));
-                    }
-                    if vs_prev != vs_cur {
-                        ans.push((j, VirtPageNum(vs_prev)..VirtPageNum(vs_cur)));
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 9)) & 511
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
+        let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
+        // 区间跨过了上一级的边界时，本级的索引到页表的末尾为止；最高一级的区间可能到达地址空间的最顶端
+        if vpn_range.end.0 >> ((level.0 + 1) * 9) > vpn_range.start.0 >> ((level.0 + 1) * 9) {
+            end = 512;
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
+            3 => (vpn.0 & !((1 << 36) - 1)) + (idx << 27),
+            _ => unimplemented!("this level does not exist on Sv48"),
+        })
+    }
+    type Entry = Sv48PageEntry;
+    type Slot = Sv48PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv48PageSlot) -> Result<&mut Sv48PageEntry, &mut Sv48PageSlot> {
+        Sv39::slot_try_get_entry(slot)
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        Sv39::init_page_table(table)
+    }
+    type Flags = Sv48Flags;
+    fn slot_set_child(slot: &mut Sv48PageSlot, ppn: PhysPageNum) {
+        Sv39::slot_set_child(slot, ppn)
+    }
+    fn slot_set_mapping(slot: &mut Sv48PageSlot, ppn: PhysPageNum, flags: Sv48Flags) {
+        Sv39::slot_set_mapping(slot, ppn, flags)
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv48PageEntry, ppn: PhysPageNum, flags: Sv48Flags) {
+        Sv39::entry_write_ppn_flags(entry, ppn, flags)
+    }
+    fn entry_get_ppn(entry: &mut Sv48PageEntry) -> PhysPageNum {
+        Sv39::entry_get_ppn(entry)
+    }
+    fn entry_get_flags(entry: &mut Sv48PageEntry) -> Sv48Flags {
+        Sv39::entry_get_flags(entry)
+    }
+    fn entry_is_leaf(entry: &mut Sv48PageEntry) -> bool {
+        Sv39::entry_is_leaf(entry)
+    }
+    fn entry_is_global(entry: &mut Sv48PageEntry) -> bool {
+        Sv39::entry_is_global(entry)
+    }
+    fn entry_clear(entry: &mut Sv48PageEntry) {
+        Sv39::entry_clear(entry)
+    }
+    fn page_table_is_empty(table: &Sv48PageTable) -> bool {
+        Sv39::page_table_is_empty(table)
+    }
+    fn flags_is_write_execute(flags: &Sv48Flags) -> bool {
+        Sv39::flags_is_write_execute(flags)
+    }
+}
+
+// Sv57分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv57;
+
+#[cfg(target_pointer_width = "64")]
+impl PageMode for Sv57 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 44;
+    type PageTable = Sv57PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(512), // 2M页
+            2 => FrameLayout::new_unchecked(512 * 512), // 1G页
+            3 => FrameLayout::new_unchecked(512 * 512 * 512), // 512G页
+            4 => FrameLayout::new_unchecked(512 * 512 * 512 * 512), // 256T页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv57")
+        } }
+    }
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1)],
+            2 => &[PageLevel(4), PageLevel(3), PageLevel(2)],
+            3 => &[PageLevel(4), PageLevel(3)],
+            4 => &[PageLevel(4)],
+            _ => unimplemented!("this level does not exist on Sv57"),
+        }
+    }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1)],
+            1 => &[PageLevel(4), PageLevel(3), PageLevel(2)],
+            2 => &[PageLevel(4), PageLevel(3)],
+            3 => &[PageLevel(4)],
+            4 => &[],
+            _ => unimplemented!("this level does not exist on Sv57"),
+        }
+    }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            2 => &[PageLevel(2), PageLevel(1), PageLevel(0)],
+            3 => &[PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            4 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv57"),
+        }
+    }
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 9)) & 511
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
+        let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
+        // 区间跨过了上一级的边界时，本级的索引到页表的末尾为止；最高一级的区间可能到达地址空间的最顶端
+        if vpn_range.end.0 >> ((level.0 + 1) * 9) > vpn_range.start.0 >> ((level.0 + 1) * 9) {
+            end = 512;
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
+            3 => (vpn.0 & !((1 << 36) - 1)) + (idx << 27),
+            4 => (vpn.0 & !((1 << 45) - 1)) + (idx << 36),
+            _ => unimplemented!("this level does not exist on Sv57"),
+        })
+    }
+    type Entry = Sv57PageEntry;
+    type Slot = Sv57PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv57PageSlot) -> Result<&mut Sv57PageEntry, &mut Sv57PageSlot> {
+        Sv39::slot_try_get_entry(slot)
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        Sv39::init_page_table(table)
+    }
+    type Flags = Sv57Flags;
+    fn slot_set_child(slot: &mut Sv57PageSlot, ppn: PhysPageNum) {
+        Sv39::slot_set_child(slot, ppn)
+    }
+    fn slot_set_mapping(slot: &mut Sv57PageSlot, ppn: PhysPageNum, flags: Sv57Flags) {
+        Sv39::slot_set_mapping(slot, ppn, flags)
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv57PageEntry, ppn: PhysPageNum, flags: Sv57Flags) {
+        Sv39::entry_write_ppn_flags(entry, ppn, flags)
+    }
+    fn entry_get_ppn(entry: &mut Sv57PageEntry) -> PhysPageNum {
+        Sv39::entry_get_ppn(entry)
+    }
+    fn entry_get_flags(entry: &mut Sv57PageEntry) -> Sv57Flags {
+        Sv39::entry_get_flags(entry)
+    }
+    fn entry_is_leaf(entry: &mut Sv57PageEntry) -> bool {
+        Sv39::entry_is_leaf(entry)
+    }
+    fn entry_is_global(entry: &mut Sv57PageEntry) -> bool {
+        Sv39::entry_is_global(entry)
+    }
+    fn entry_clear(entry: &mut Sv57PageEntry) {
+        Sv39::entry_clear(entry)
+    }
+    fn page_table_is_empty(table: &Sv57PageTable) -> bool {
+        Sv39::page_table_is_empty(table)
+    }
+    fn flags_is_write_execute(flags: &Sv57Flags) -> bool {
+        Sv39::flags_is_write_execute(flags)
+    }
+}
+
+// Sv32分页系统模式；RISC-V RV32下有效。64位的内核不使用它，只在主机上的测试中编译
+#[cfg(any(test, target_pointer_width = "32"))]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv32;
+
+#[cfg(any(test, target_pointer_width = "32"))]
+impl PageMode for Sv32 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 22;
+    type PageTable = Sv32PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(1024), // 4M页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv32")
+        } }
+    }
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(1)],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(1)],
+            1 => &[],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 10)) & 1023
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 10)) & 1023;
+        let mut end = (vpn_range.end.0 >> (level.0 * 10)) & 1023;
+        if level.0 == 0 {
+            let start_idx1 = vpn_range.start.0 >> 10;
+            let end_idx1 = vpn_range.end.0 >> 10;
+            if end_idx1 > start_idx1 {
+                end = 1024;
+            }
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 10) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 20) - 1)) + (idx << 10),
+            _ => unimplemented!("this level does not exist on Sv32"),
+        })
+    }
+    type Entry = Sv32PageEntry;
+    type Slot = Sv32PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv32PageSlot) -> Result<&mut Sv32PageEntry, &mut Sv32PageSlot> {
+        // note(unsafe): slot是合法的
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        if ans.flags().contains(Sv32Flags::V) {
+            Ok(ans)
+        } else {
+            Err(slot)
+        }
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() }; // 全零
+    }
+    type Flags = Sv32Flags;
+    fn slot_set_child(slot: &mut Sv32PageSlot, ppn: PhysPageNum) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv32Flags::V); // V=1, R=W=X=0
+    }
+    fn slot_set_mapping(slot: &mut Sv32PageSlot, ppn: PhysPageNum, flags: Sv32Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv32Flags::V | flags);
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv32PageEntry, ppn: PhysPageNum, flags: Sv32Flags) {
+        entry.write_ppn_flags(ppn, flags);
+    }
+    fn entry_get_ppn(entry: &mut Sv32PageEntry) -> PhysPageNum {
+        entry.ppn()
+    }
+    fn entry_get_flags(entry: &mut Sv32PageEntry) -> Sv32Flags {
+        entry.flags()
+    }
+    fn entry_is_leaf(entry: &mut Sv32PageEntry) -> bool {
+        entry.flags().intersects(Sv32Flags::R | Sv32Flags::W | Sv32Flags::X)
+    }
+    fn entry_is_global(entry: &mut Sv32PageEntry) -> bool {
+        entry.flags().contains(Sv32Flags::G)
+    }
+    fn entry_clear(entry: &mut Sv32PageEntry) {
+        entry.bits = 0;
+    }
+    fn page_table_is_empty(table: &Sv32PageTable) -> bool {
+        table.entries.iter().all(|slot| slot.bits & Sv32Flags::V.bits() as u32 == 0)
+    }
+    fn flags_is_write_execute(flags: &Sv32Flags) -> bool {
+        flags.contains(Sv32Flags::W | Sv32Flags::X)
+    }
+}
+
+#[cfg(any(test, target_pointer_width = "32"))]
+#[repr(C)]
+pub struct Sv32PageTable {
+    entries: [Sv32PageSlot; 1024],
+}
+
+#[cfg(any(test, target_pointer_width = "32"))]
+impl core::ops::Index<usize> for Sv32PageTable {
+    type Output = Sv32PageSlot;
+    fn index(&self, idx: usize) -> &Sv32PageSlot {
+        &self.entries[idx]
+    }
+}
+
+#[cfg(any(test, target_pointer_width = "32"))]
+impl core::ops::IndexMut<usize> for Sv32PageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv32PageSlot {
+        &mut self.entries[idx]
+    }
+}
+
+// Sv32的页表项只有32位，无论在哪种位宽的处理器上都是如此
+#[cfg(any(test, target_pointer_width = "32"))]
+#[repr(C)]
+pub struct Sv32PageSlot {
+    bits: u32,
+}
+
+#[cfg(any(test, target_pointer_width = "32"))]
+#[repr(C)]
+pub struct Sv32PageEntry {
+    bits: u32,
+}
+
+#[cfg(any(test, target_pointer_width = "32"))]
+impl Sv32PageEntry {
+    #[inline]
+    pub fn ppn(&self) -> PhysPageNum {
+        PhysPageNum(self.bits.get_bits(10..32) as usize)
+    }
+    #[inline]
+    pub fn flags(&self) -> Sv32Flags {
+        Sv32Flags::from_bits_truncate(self.bits.get_bits(0..10) as u16)
+    }
+    #[inline]
+    pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv32Flags) {
+        self.bits = ((ppn.0 as u32) << 10) | flags.bits() as u32
+    }
+}
+
+// Sv32页表项的设置与Sv39相同
+#[cfg(any(test, target_pointer_width = "32"))]
+pub type Sv32Flags = Sv39Flags;
+
+// 表示一个分页系统实现的地址空间
+//
+// 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
+#[derive(Debug)]
+pub struct PagedAddrSpace<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
+    root_frame: FrameBox<A>,
+    frames: Vec<FrameBox<A>>,
+    // 地址空间自己拥有的4K叶子页帧，按虚拟页号索引；取消映射或地址空间被释放时，页帧也被释放
+    leaf_frames: BTreeMap<VirtPageNum, FrameBox<A>>,
+    // 已经预留、但还没有分配页帧的区间，按开始的虚拟页号索引，值为区间的结束和映射的设置
+    lazy_areas: BTreeMap<VirtPageNum, (VirtPageNum, M::Flags)>,
+    frame_alloc: A,
+    page_mode: M,
+    asid: Option<AsidTag>,
+    global: GlobalRoots,
+}
+
+// 根页表中的全局页表项属于谁
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum GlobalRoots {
+    // 没有全局页表项
+    None,
+    // 全局页表项和下一级页表属于这个地址空间，比如内核的地址空间
+    Owned,
+    // 全局页表项从内核的地址空间复制而来，下一级页表属于内核，不能修改
+    Borrowed,
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 创建一个空的分页地址空间。一定会产生内存的写操作
+    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
+        // 新建一个满足根页表对齐要求的帧；虽然代码没有体现，通常对齐要求是1
+        let mut root_frame = FrameBox::try_new_in(frame_alloc.clone())?;
+        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
+        // 向帧里填入一个空的根页表 
+        unsafe { fill_frame_with_initialized_page_table::<A, M>(&mut root_frame) };
+        Ok(Self { 
+            root_frame, frames: Vec::new(), leaf_frames: BTreeMap::new(), lazy_areas: BTreeMap::new(), 
+            frame_alloc, page_mode, asid: None, global: GlobalRoots::None 
+        })
+    }
+    // 创建一个新的地址空间，根页表共享kernel中所有全局的根页表项，从而不需要重新建立内核的映射。
+    // 新的地址空间不能修改全局根页表项覆盖的区间。
+    //
+    // 调用者需要保证kernel比新的地址空间活得更久，并且不再取消全局根页表项
+    pub unsafe fn try_new_with_global(kernel: &Self) -> Result<Self, FrameAllocError> {
+        let mut ans = Self::try_new_in(kernel.page_mode, kernel.frame_alloc.clone())?;
+        let src_table = unref_ppn_mut::<M, _>(&kernel.frame_alloc, kernel.root_page_number());
+        let dst_table = unref_ppn_mut::<M, _>(&ans.frame_alloc, ans.root_page_number());
+        for vidx in 0..page_table_len::<M>() {
+            if let Ok(entry) = M::slot_try_get_entry(&mut src_table[vidx]) {
+                if M::entry_is_global(entry) {
+                    M::slot_set_mapping(&mut dst_table[vidx], M::entry_get_ppn(entry), M::entry_get_flags(entry));
+                    ans.global = GlobalRoots::Borrowed;
+                }
+            }
+        }
+        Ok(ans)
+    }
+    // 得到根页表的地址
+    pub fn root_page_number(&self) -> PhysPageNum {
+        self.root_frame.phys_page_num()
+    }
+    // 切换到这个地址空间。从ASID_MANAGER得到地址空间编号，修改映射后，将按这个编号刷新页表缓存；
+    // 编号回绕到新的一代时，刷新所有的页表缓存
+    pub unsafe fn activate(&mut self, activate: unsafe fn(PhysPageNum, AddressSpaceId)) {
+        let (tag, flush_all) = ASID_MANAGER.lock().activate(self.asid);
+        self.asid = Some(tag);
+        activate(self.root_page_number(), tag.asid());
+        if flush_all {
+            sfence_vma_all();
+        }
+    }
+    // 和activate相同地分配地址空间编号，但不写入satp，而是返回应当写入satp的值。
+    // 用户的地址空间由跳板页在回到用户之前写入satp
+    pub unsafe fn prepare_activate(&mut self, satp: fn(PhysPageNum, AddressSpaceId) -> usize) -> usize {
+        let (tag, flush_all) = ASID_MANAGER.lock().activate(self.asid);
+        self.asid = Some(tag);
+        if flush_all {
+            sfence_vma_all();
+        }
+        satp(self.root_page_number(), tag.asid())
+    }
+    // 软件遍历页表，得到虚拟地址对应的物理地址、页表项的设置和所在页表的等级；如果没有映射，返回None
+    pub fn translate(&self, va: VirtAddr) -> Option<(PhysAddr, M::Flags, PageLevel)> {
+        let vpn = va.page_number::<M>();
+        let (leaf_vpn, leaf_ppn, flags, level) = unsafe { self.find_leaf(vpn) }.ok()?;
+        let ppn = PhysPageNum(leaf_ppn.0 + (vpn.0 - leaf_vpn.0));
+        let pa = PhysAddr(ppn.addr_begin::<M>().0 + va.page_offset::<M>());
+        Some((pa, flags, level))
+    }
+    // 遍历一段虚拟页号区间内的所有叶子页表项，按虚拟地址从低到高的顺序
+    #[cfg(test)]
+    pub fn walk(&self, vpn: VirtPageNum, n: usize) -> PageWalk<'_, M, A> {
+        PageWalk { space: self, current: vpn, end: VirtPageNum(vpn.0 + n) }
+    }
+    // 从根页表开始寻找包含vpn的叶子页表项，返回叶子页表项开始的虚拟页号、物理页号、设置和等级。
+    // 如果在某一级页表中没有找到映射，返回这一级页表项覆盖的区间，从而可以跳过整个区间
+    unsafe fn find_leaf(&self, vpn: VirtPageNum) -> Result<(VirtPageNum, PhysPageNum, M::Flags, PageLevel), Range<VirtPageNum>> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_until(PageLevel::leaf_level()) {
+            let page_table = unref_ppn_mut::<M, _>(&self.frame_alloc, ppn);
+            let align = M::get_layout_for_level(level).frame_align();
+            let level_vpn = VirtPageNum(vpn.0 & !(align - 1)); // 这一级页表项覆盖区间的开始
+            match M::slot_try_get_entry(&mut page_table[M::vpn_index(vpn, level)]) {
+                Ok(entry) => if M::entry_is_leaf(entry) {
+                    return Ok((level_vpn, M::entry_get_ppn(entry), M::entry_get_flags(entry), level))
+                } else {
+                    ppn = M::entry_get_ppn(entry)
+                },
+                Err(_slot) => return Err(level_vpn..VirtPageNum(level_vpn.0 + align)),
+            }
+        }
+        // 最低一级的页表项一定是叶子节点，走到这里说明页表不合法，当作没有映射处理
+        Err(vpn..VirtPageNum(vpn.0 + 1))
+    }
+    // 寻找包含vpn的叶子页表项所在的页表，返回页表的物理页号和等级；如果没有映射，返回None
+    unsafe fn find_leaf_table(&self, vpn: VirtPageNum) -> Option<(PhysPageNum, PageLevel)> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_until(PageLevel::leaf_level()) {
+            let page_table = unref_ppn_mut::<M, _>(&self.frame_alloc, ppn);
+            let entry = M::slot_try_get_entry(&mut page_table[M::vpn_index(vpn, level)]).ok()?;
+            if M::entry_is_leaf(entry) {
+                return Some((ppn, level))
+            }
+            ppn = M::entry_get_ppn(entry);
+        }
+        None
+    }
+}
+
+impl<M: PageMode, A: FrameAllocator> Drop for PagedAddrSpace<M, A> {
+    fn drop(&mut self) {
+        // 回收地址空间编号，并刷新这个编号的页表缓存，以便分配给其它地址空间
+        if let Some(tag) = self.asid {
+            if ASID_MANAGER.lock().deallocate(tag) {
+                unsafe { sfence_vma_asid(tag.asid().0 as usize) };
+            }
+        }
+    }
+}
+
+// 遍历地址空间中一段区间内叶子页表项的迭代器
+//
+// 每一项包括叶子页表项开始的虚拟页号、物理页号、设置和所在页表的等级。
+// 如果区间只覆盖了大页的一部分，也将返回整个大页的页表项，使用者需要自己计算需要的部分。
+#[cfg(test)]
+#[derive(Debug)]
+pub struct PageWalk<'a, M: PageMode, A: FrameAllocator> {
+    space: &'a PagedAddrSpace<M, A>,
+    current: VirtPageNum,
+    end: VirtPageNum,
+}
+
+#[cfg(test)]
+impl<'a, M: PageMode, A: FrameAllocator + Clone> Iterator for PageWalk<'a, M, A> {
+    type Item = (VirtPageNum, PhysPageNum, M::Flags, PageLevel);
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.current.0 < self.end.0 {
+            match unsafe { self.space.find_leaf(self.current) } {
+                Ok(ans) => {
+                    let align = M::get_layout_for_level(ans.3).frame_align();
+                    self.current = VirtPageNum(ans.0.0 + align);
+                    return Some(ans)
+                },
+                Err(unmapped) => self.current = unmapped.end, // 跳过没有映射的区间
+            }
+        }
+        None
+    }
+}
+
+#[inline] unsafe fn unref_ppn_mut<'a, M: PageMode, P: PhysMemory + ?Sized>(mem: &P, ppn: PhysPageNum) -> &'a mut M::PageTable {
+    let pa = ppn.addr_begin::<M>();
+    &mut *(mem.phys_to_virt(pa).0 as *mut M::PageTable)
+}
+
+// 复制一整个页帧的内容
+#[inline] unsafe fn copy_frame<M: PageMode, P: PhysMemory + ?Sized>(mem: &P, src: PhysPageNum, dst: PhysPageNum) {
+    let src = mem.phys_to_virt(src.addr_begin::<M>()).0 as *const u8;
+    let dst = mem.phys_to_virt(dst.addr_begin::<M>()).0 as *mut u8;
+    core::ptr::copy_nonoverlapping(src, dst, 1 << M::FRAME_SIZE_BITS);
+}
+
+// 把页帧的内容填满零
+#[inline] unsafe fn zero_frame<M: PageMode, P: PhysMemory + ?Sized>(mem: &P, ppn: PhysPageNum) {
+    let dst = mem.phys_to_virt(ppn.addr_begin::<M>()).0 as *mut u8;
+    core::ptr::write_bytes(dst, 0, 1 << M::FRAME_SIZE_BITS);
+}
+
+// 一个页表包含的页表项数量。所有等级的页表大小相同，都等于一个最小的页帧
+#[inline] fn page_table_len<M: PageMode>() -> usize {
+    M::get_layout_for_level(PageLevel(1)).frame_align() / M::get_layout_for_level(PageLevel(0)).frame_align()
+}
+
+#[inline] unsafe fn fill_frame_with_initialized_page_table<A: FrameAllocator, M: PageMode>(b: &mut FrameBox<A>) {
+    let a = unref_ppn_mut::<M, _>(&b.frame_alloc, b.ppn);
+    M::init_page_table(a);
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {    
+    // 设置entry。如果寻找的过程中，中间的页表没创建，那么创建它们
+    unsafe fn alloc_get_table(&mut self, entry_level: PageLevel, vpn_start: VirtPageNum) -> Result<&mut M::PageTable, FrameAllocError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_before(entry_level) {
+            // println!("[] BEFORE PPN = {:x?}", ppn);
+            let page_table = unref_ppn_mut::<M, _>(&self.frame_alloc, ppn);
+            let vidx = M::vpn_index(vpn_start, level);
+            match M::slot_try_get_entry(&mut page_table[vidx]) {
+                Ok(entry) => {
+                    self.check_not_borrowed(entry);
+                    ppn = M::entry_get_ppn(entry)
+                },
+                Err(mut slot) => {  // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
+                    let mut frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+                    // 回收的页帧可能包含旧的数据，需要先填入空的页表
+                    fill_frame_with_initialized_page_table::<A, M>(&mut frame_box);
+                    M::slot_set_child(&mut slot, frame_box.phys_page_num());
+                    // println!("[] Created a new frame box");
+                    ppn = frame_box.phys_page_num();
+                    self.frames.push(frame_box);
+                }
+            }
+        }
+        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
+        let page_table = unref_ppn_mut::<M, _>(&self.frame_alloc, ppn); // 此时ppn是当前所需要修改的页表
+        // 创建了一个没有约束的生命周期。不过我们可以判断它是合法的，因为它的所有者是Self，在Self的周期内都合法
+        Ok(&mut *(page_table as *mut _))
+    }
+    // 建立从vpn开始n个页到ppn开始的物理页的映射。拒绝建立同时可写和可执行的映射，
+    // 如果确实需要，使用allocate_map_write_execute明确说明
+    pub fn allocate_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        if M::flags_is_write_execute(&flags) {
+            panic!("refuse to map writable and executable pages, flags: {:?}", flags)
+        }
+        self.allocate_map_write_execute(vpn, ppn, n, flags)
+    }
+    // 和allocate_map相同，但允许建立同时可写和可执行的映射
+    pub fn allocate_map_write_execute(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        debug_assert!(ppn.0.checked_add(n).map(|end| end <= 1 << M::PPN_BITS).unwrap_or(false), 
+            "physical page number out of range, ppn: {:x?}, n: {}", ppn, n);
+        for (page_level, vpn_range) in MapPairs::solve(vpn, ppn, n, self.page_mode) {
+            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
+            let align = M::get_layout_for_level(page_level).frame_align();
+            // 区间可能跨过多个同一级的页表，每次只处理一个页表
+            let mut vpn_cur = vpn_range.start;
+            while vpn_cur != vpn_range.end {
+                let table = unsafe { self.alloc_get_table(page_level, vpn_cur) }?;
+                let idx_range = M::vpn_index_range(vpn_cur..vpn_range.end, page_level);
+                // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
+                for vidx in idx_range.clone() {
+                    let this_ppn = PhysPageNum(ppn.0.wrapping_sub(vpn.0).wrapping_add(M::vpn_level_index(vpn_cur, page_level, vidx).0));
+                    // println!("[kernel-alloc-map-test] Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
+                    match M::slot_try_get_entry(&mut table[vidx]) {
+                        Ok(_entry) => panic!("already allocated"),
+                        Err(slot) => M::slot_set_mapping(slot, this_ppn, flags.clone())
+                    }
+                }
+                vpn_cur = VirtPageNum(M::vpn_level_index(vpn_cur, page_level, idx_range.end - 1).0 + align);
+            }
+        }
+        Ok(())
+    }
+    // 分配n个填满零的页帧，按flags映射到从vpn开始的区间。这些页帧归地址空间所有，
+    // 取消映射或者地址空间被释放时一起释放。分配失败时，已经映射的页也被取消映射
+    pub fn allocate_map_owned(&mut self, vpn: VirtPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        if M::flags_is_write_execute(&flags) {
+            panic!("refuse to map writable and executable pages, flags: {:?}", flags)
+        }
+        self.allocate_map_owned_write_execute(vpn, n, flags)
+    }
+    // 和allocate_map_owned相同，但允许建立同时可写和可执行的映射
+    pub fn allocate_map_owned_write_execute(&mut self, vpn: VirtPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        for i in 0..n {
+            let cur = VirtPageNum(vpn.0 + i);
+            let ans = FrameBox::try_new_in(self.frame_alloc.clone()).and_then(|frame_box| {
+                unsafe { zero_frame::<M, _>(&self.frame_alloc, frame_box.phys_page_num()) };
+                self.allocate_map_write_execute(cur, frame_box.phys_page_num(), 1, flags.clone())?;
+                self.leaf_frames.insert(cur, frame_box);
+                Ok(())
+            });
+            if let Err(e) = ans {
+                if i > 0 {
+                    self.unmap(vpn, i).expect("unmap 4K pages");
+                }
+                return Err(e)
+            }
+        }
+        Ok(())
+    }
+    // 预留从vpn开始n个页的区间，暂时不分配页帧。第一次访问其中的页时将产生缺页异常，
+    // 由resolve_lazy_fault分配填满零的页帧，再按flags建立映射
+    pub fn reserve_lazy(&mut self, vpn: VirtPageNum, n: usize, flags: M::Flags) {
+        if M::flags_is_write_execute(&flags) {
+            panic!("refuse to reserve writable and executable pages, flags: {:?}", flags)
+        }
+        let vpn_end = VirtPageNum(vpn.0 + n);
+        if let Some((_, (end, _))) = self.lazy_areas.range(..vpn_end).next_back() {
+            if end.0 > vpn.0 {
+                panic!("already reserved")
+            }
+        }
+        self.lazy_areas.insert(vpn, (vpn_end, flags));
+    }
+    // 处理访问va产生的缺页异常。如果va在预留的区间中，并且还没有映射，分配页帧并映射，返回true；
+    // 否则这是一次真正的非法访问，返回false
+    pub fn resolve_lazy_fault(&mut self, va: VirtAddr) -> Result<bool, FrameAllocError> {
+        let vpn = va.page_number::<M>();
+        let flags = match self.lazy_areas.range(..=vpn).next_back() {
+            Some((_, (end, flags))) if vpn.0 < end.0 => flags.clone(),
+            _ => return Ok(false),
+        };
+        if unsafe { self.find_leaf_table(vpn) }.is_some() {
+            return Ok(false) // 已经分配过页帧，说明是访问权限不对
+        }
+        let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+        unsafe { zero_frame::<M, _>(&self.frame_alloc, frame_box.phys_page_num()) };
+        self.allocate_map(vpn, frame_box.phys_page_num(), 1, flags)?;
+        self.leaf_frames.insert(vpn, frame_box);
+        self.flush_tlb(vpn, 1);
+        Ok(true)
+    }
+    // 取消区间内的预留；和区间部分重叠的预留区间将被截断或拆分
+    fn cancel_lazy(&mut self, vpn_range: Range<VirtPageNum>) {
+        let overlapped: Vec<_> = self.lazy_areas.range(..vpn_range.end)
+            .filter(|(_, (end, _))| end.0 > vpn_range.start.0)
+            .map(|(&start, _)| start).collect();
+        for start in overlapped {
+            let (end, flags) = self.lazy_areas.remove(&start).unwrap();
+            if start.0 < vpn_range.start.0 {
+                self.lazy_areas.insert(start, (vpn_range.start, flags.clone()));
+            }
+            if end.0 > vpn_range.end.0 {
+                self.lazy_areas.insert(vpn_range.end, (end, flags));
+            }
+        }
+    }
+    // 取消从vpn开始n个页的映射。如果区间只覆盖了大页的一部分，先把大页拆分成下一级的页，再取消映射；
+    // 拆分大页需要分配新的页表，因此可能失败。变为空的中间页表将被释放
+    pub fn unmap(&mut self, vpn: VirtPageNum, n: usize) -> Result<(), FrameAllocError> {
+        let vpn_end = VirtPageNum(vpn.0 + n);
+        let root_level = M::visit_levels_until(PageLevel::leaf_level())[0];
+        let root_ppn = self.root_frame.phys_page_num();
+        unsafe { self.unmap_in_table(root_ppn, root_level, vpn..vpn_end) }?;
+        // 释放区间内地址空间自己拥有的页帧
+        let owned: Vec<_> = self.leaf_frames.range(vpn..vpn_end).map(|(&vpn, _)| vpn).collect();
+        for vpn in owned {
+            self.leaf_frames.remove(&vpn);
+        }
+        self.cancel_lazy(vpn..vpn_end);
+        self.flush_tlb(vpn, n);
+        Ok(())
+    }
+    // 在ppn指向的页表中取消区间内的映射；返回这个页表是否已经变为空的页表
+    unsafe fn unmap_in_table(&mut self, ppn: PhysPageNum, level: PageLevel, vpn_range: Range<VirtPageNum>) -> Result<bool, FrameAllocError> {
+        let table = unref_ppn_mut::<M, _>(&self.frame_alloc, ppn);
+        let align = M::get_layout_for_level(level).frame_align();
+        // vpn_range一定在这个页表覆盖的范围内，但不一定按这一级对齐
+        let idx_start = M::vpn_index(vpn_range.start, level);
+        let idx_end = M::vpn_index(VirtPageNum(vpn_range.end.0 - 1), level);
+        for vidx in idx_start..=idx_end {
+            let entry_start = M::vpn_level_index(vpn_range.start, level, vidx);
+            let entry_end = VirtPageNum(entry_start.0 + align);
+            // 这个页表项覆盖的区间与需要取消映射区间的交集
+            let start = VirtPageNum(usize::max(entry_start.0, vpn_range.start.0));
+            let end = VirtPageNum(usize::min(entry_end.0, vpn_range.end.0));
+            let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => continue, // 本来就没有映射，跳过
+            };
+            self.check_not_borrowed(entry);
+            if M::entry_is_leaf(entry) {
+                if start == entry_start && end == entry_end {
+                    M::entry_clear(entry);
+                    continue;
+                }
+                // 只取消大页的一部分，需要把大页拆分成下一级的页
+                self.split_leaf(table, vidx, level)?;
+            }
+            let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => unreachable!(),
+            };
+            let child_ppn = M::entry_get_ppn(entry);
+            let child_level = M::visit_levels_from(level)[1];
+            if self.unmap_in_table(child_ppn, child_level, start..end)? {
+                // 下一级页表已经为空，释放它
+                M::entry_clear(entry);
+                self.frames.retain(|frame_box| frame_box.phys_page_num() != child_ppn);
+            }
+        }
+        Ok(M::page_table_is_empty(table))
+    }
+    // 把table中第vidx项的大页拆分成下一级的页，映射的物理页和设置都不变
+    unsafe fn split_leaf(&mut self, table: &mut M::PageTable, vidx: usize, level: PageLevel) -> Result<(), FrameAllocError> {
+        let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+            Ok(entry) => entry,
+            Err(_slot) => unreachable!(),
+        };
+        let align = M::get_layout_for_level(level).frame_align();
+        let child_level = M::visit_levels_from(level)[1];
+        let child_align = M::get_layout_for_level(child_level).frame_align();
+        let mut frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+        fill_frame_with_initialized_page_table::<A, M>(&mut frame_box);
+        let child_table = unref_ppn_mut::<M, _>(&self.frame_alloc, frame_box.phys_page_num());
+        let (leaf_ppn, flags) = (M::entry_get_ppn(entry), M::entry_get_flags(entry));
+        for cidx in 0..align / child_align {
+            let this_ppn = PhysPageNum(leaf_ppn.0 + cidx * child_align);
+            M::slot_set_mapping(&mut child_table[cidx], this_ppn, flags.clone());
+        }
+        M::slot_set_child(&mut table[vidx], frame_box.phys_page_num());
+        self.frames.push(frame_box);
+        Ok(())
+    }
+    // 从内核借来的全局页表项指向内核的页表，不能通过这个地址空间修改
+    fn check_not_borrowed(&self, entry: &mut M::Entry) {
+        if self.global == GlobalRoots::Borrowed && M::entry_is_global(entry) {
+            panic!("cannot modify global kernel mappings from a borrowing address space")
+        }
+    }
+    // 取消映射后刷新页表缓存。页数较多时，直接刷新整个地址空间编号
+    fn flush_tlb(&self, vpn: VirtPageNum, n: usize) {
+        const FLUSH_ALL_THRESHOLD: usize = 64;
+        let asid = match self.asid {
+            // 按地址空间编号刷新时，不会刷新全局映射
+            Some(tag) if self.global != GlobalRoots::Owned => tag.asid().0 as usize,
+            _ => { // 不知道地址空间编号，或者有全局映射，只能刷新所有的页表缓存
+                unsafe { sfence_vma_all() };
+                return
+            }
+        };
+        if n > FLUSH_ALL_THRESHOLD {
+            unsafe { sfence_vma_asid(asid) };
+            return;
+        }
+        for i in 0..n {
+            let va = (vpn.0 + i) << M::FRAME_SIZE_BITS;
+            unsafe { sfence_vma(va, asid) };
+        }
+    }
+}
+
+// 刷新页表缓存的指令。在主机上测试时没有页表缓存，这些函数什么也不做
+unsafe fn sfence_vma_all() {
+    #[cfg(not(test))]
+    asm!("sfence.vma");
+}
+
+unsafe fn sfence_vma_asid(asid: usize) {
+    #[cfg(not(test))]
+    asm!("sfence.vma zero, {}", in(reg) asid);
+    #[cfg(test)]
+    let _ = asid;
+}
+
+unsafe fn sfence_vma(va: usize, asid: usize) {
+    #[cfg(not(test))]
+    asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid);
+    #[cfg(test)]
+    let _ = (va, asid);
+}
+
+// 写时复制需要用到页表项的软件位，目前只有RISC-V的页表项支持
+impl<M: PageMode<Flags = Sv39Flags>, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 复制出一个新的地址空间，和当前地址空间共享所有的叶子页帧。
+    //
+    // 用户可写的页在两个地址空间中都变为只读，并标记为写时复制；写入它们将产生缺页异常，
+    // 由resolve_cow_fault复制页帧后再恢复写权限。内核的页、用户只读的页和共享内存的页直接共享。
+    // 新的地址空间没有地址空间编号，需要调用者另外分配。内核还没有fork系统调用，暂时只在测试中使用
+    #[cfg(test)]
+    pub fn fork_cow(&mut self) -> Result<Self, FrameAllocError> {
+        let mut child = Self::try_new_in(self.page_mode, self.frame_alloc.clone())?;
+        let root_level = M::visit_levels_until(PageLevel::leaf_level())[0];
+        let (src_ppn, dst_ppn) = (self.root_frame.phys_page_num(), child.root_frame.phys_page_num());
+        unsafe { self.fork_table(&mut child, src_ppn, dst_ppn, root_level) }?;
+        for (&vpn, frame_box) in &self.leaf_frames {
+            child.leaf_frames.insert(vpn, frame_box.share());
+        }
+        child.lazy_areas = self.lazy_areas.clone();
+        // 当前地址空间的可写页变为只读，需要刷新整个地址空间编号的页表缓存
+        self.flush_tlb(VirtPageNum(0), usize::MAX);
+        Ok(child)
+    }
+    // 把src_ppn页表中的映射复制到child地址空间的dst_ppn页表中；中间页表在child中重新分配
+    #[cfg(test)]
+    unsafe fn fork_table(&mut self, child: &mut Self, src_ppn: PhysPageNum, dst_ppn: PhysPageNum, level: PageLevel) -> Result<(), FrameAllocError> {
+        let src_table = unref_ppn_mut::<M, _>(&self.frame_alloc, src_ppn);
+        let dst_table = unref_ppn_mut::<M, _>(&self.frame_alloc, dst_ppn);
+        for vidx in 0..page_table_len::<M>() {
+            let entry = match M::slot_try_get_entry(&mut src_table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => continue,
+            };
+            let ppn = M::entry_get_ppn(entry);
+            if M::entry_is_leaf(entry) {
+                let mut flags = M::entry_get_flags(entry);
+                if flags.contains(Sv39Flags::U | Sv39Flags::W) && !flags.contains(Sv39Flags::SHARED) {
+                    flags.remove(Sv39Flags::W);
+                    flags.insert(Sv39Flags::COW);
+                    M::entry_write_ppn_flags(entry, ppn, flags);
+                }
+                M::slot_set_mapping(&mut dst_table[vidx], ppn, flags);
+            } else if M::entry_is_global(entry) {
+                // 全局映射的下一级页表属于内核，直接共享
+                M::slot_set_mapping(&mut dst_table[vidx], ppn, M::entry_get_flags(entry));
+                child.global = GlobalRoots::Borrowed;
+            } else {
+                let mut frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+                fill_frame_with_initialized_page_table::<A, M>(&mut frame_box);
+                let child_ppn = frame_box.phys_page_num();
+                M::slot_set_child(&mut dst_table[vidx], child_ppn);
+                child.frames.push(frame_box);
+                let child_level = M::visit_levels_from(level)[1];
+                self.fork_table(child, ppn, child_ppn, child_level)?;
+            }
+        }
+        Ok(())
+    }
+    // 处理写入va产生的缺页异常。如果va所在的页是写时复制的页，复制页帧并恢复写权限，返回true；
+    // 否则这是一次真正的非法写入，返回false。
+    //
+    // 如果页帧只被当前地址空间拥有，不需要复制，直接恢复写权限。大页将被拆分，只复制写入的4K页
+    pub fn resolve_cow_fault(&mut self, va: VirtAddr) -> Result<bool, FrameAllocError> {
+        let vpn = va.page_number::<M>();
+        loop {
+            let (table_ppn, level) = match unsafe { self.find_leaf_table(vpn) } {
+                Some(ans) => ans,
+                None => return Ok(false),
+            };
+            let table = unsafe { unref_ppn_mut::<M, _>(&self.frame_alloc, table_ppn) };
+            let vidx = M::vpn_index(vpn, level);
+            let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => unreachable!(),
+            };
+            let (ppn, mut flags) = (M::entry_get_ppn(entry), M::entry_get_flags(entry));
+            if !flags.contains(Sv39Flags::COW) {
+                return Ok(false)
+            }
+            if level != PageLevel::leaf_level() {
+                unsafe { self.split_leaf(table, vidx, level) }?;
+                continue;
+            }
+            flags.remove(Sv39Flags::COW);
+            flags.insert(Sv39Flags::W);
+            let owned_alone = match self.leaf_frames.get(&vpn) {
+                Some(frame_box) => frame_box.phys_page_num() == ppn && frame_box.ref_count() == 1,
+                None => false,
+            };
+            if owned_alone {
+                M::entry_write_ppn_flags(entry, ppn, flags);
+            } else {
+                let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+                unsafe { copy_frame::<M, _>(&self.frame_alloc, ppn, frame_box.phys_page_num()) };
+                M::entry_write_ppn_flags(entry, frame_box.phys_page_num(), flags);
+                // 如果原来拥有共享的页帧，替换它将减少一次引用
+                self.leaf_frames.insert(vpn, frame_box);
+            }
+            self.flush_tlb(vpn, 1);
+            return Ok(true)
+        }
+    }
+    // 把覆盖从vpn开始n个页的根页表项标记为全局，try_new_with_global创建的地址空间将共享这些根页表项。
+    // 这些根页表项覆盖的区间中只能有全局映射，比如内核的映射；映射需要已经建立
+    pub fn set_global(&mut self, vpn: VirtPageNum, n: usize) {
+        let root_level = M::visit_levels_until(PageLevel::leaf_level())[0];
+        let table = unsafe { unref_ppn_mut::<M, _>(&self.frame_alloc, self.root_frame.phys_page_num()) };
+        let idx_start = M::vpn_index(vpn, root_level);
+        let idx_end = M::vpn_index(VirtPageNum(vpn.0 + n - 1), root_level);
+        for vidx in idx_start..=idx_end {
+            let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => panic!("no mapping to set global at root index {}", vidx),
+            };
+            let (ppn, flags) = (M::entry_get_ppn(entry), M::entry_get_flags(entry));
+            M::entry_write_ppn_flags(entry, ppn, flags | Sv39Flags::G);
+        }
+        self.global = GlobalRoots::Owned;
+        self.flush_tlb(vpn, n);
+    }
+    // 修改从vpn开始n个页的访问权限。flags中只有R、W、X和U位有效，其它位保持原来的设置；
+    // 区间中没有映射的页被跳过，预留的页将在分配时使用新的权限。
+    //
+    // 如果区间只覆盖了大页的一部分，先把大页拆分成下一级的页；拆分大页需要分配新的页表，因此可能失败。
+    // 写时复制的页或者和其它地址空间共享的页帧，要求可写时只标记为写时复制，第一次写入时再复制
+    pub fn protect(&mut self, vpn: VirtPageNum, n: usize, flags: Sv39Flags) -> Result<(), FrameAllocError> {
+        let flags = flags & (Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::U);
+        if M::flags_is_write_execute(&flags) {
+            panic!("refuse to protect pages as writable and executable, flags: {:?}", flags)
+        }
+        if !flags.intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X) {
+            // 没有R、W、X位的页表项将被当作指向下一级页表的页表项
+            panic!("refuse to protect pages as inaccessible, use unmap instead")
+        }
+        let vpn_end = VirtPageNum(vpn.0 + n);
+        let root_level = M::visit_levels_until(PageLevel::leaf_level())[0];
+        let root_ppn = self.root_frame.phys_page_num();
+        unsafe { self.protect_in_table(root_ppn, root_level, vpn..vpn_end, flags) }?;
+        self.protect_lazy(vpn..vpn_end, flags);
+        self.flush_tlb(vpn, n);
+        Ok(())
+    }
+    // 在ppn指向的页表中修改区间内叶子页表项的权限
+    unsafe fn protect_in_table(&mut self, ppn: PhysPageNum, level: PageLevel, vpn_range: Range<VirtPageNum>, flags: Sv39Flags) -> Result<(), FrameAllocError> {
+        let table = unref_ppn_mut::<M, _>(&self.frame_alloc, ppn);
+        let align = M::get_layout_for_level(level).frame_align();
+        let idx_start = M::vpn_index(vpn_range.start, level);
+        let idx_end = M::vpn_index(VirtPageNum(vpn_range.end.0 - 1), level);
+        for vidx in idx_start..=idx_end {
+            let entry_start = M::vpn_level_index(vpn_range.start, level, vidx);
+            let entry_end = VirtPageNum(entry_start.0 + align);
+            let start = VirtPageNum(usize::max(entry_start.0, vpn_range.start.0));
+            let end = VirtPageNum(usize::min(entry_end.0, vpn_range.end.0));
+            let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => continue,
+            };
+            self.check_not_borrowed(entry);
+            if M::entry_is_leaf(entry) {
+                if start == entry_start && end == entry_end {
+                    let (leaf_ppn, old_flags) = (M::entry_get_ppn(entry), M::entry_get_flags(entry));
+                    let new_flags = self.protected_flags(entry_start, old_flags, flags);
+                    M::entry_write_ppn_flags(entry, leaf_ppn, new_flags);
+                    continue;
+                }
+                self.split_leaf(table, vidx, level)?;
+            }
+            let entry = match M::slot_try_get_entry(&mut table[vidx]) {
+                Ok(entry) => entry,
+                Err(_slot) => unreachable!(),
+            };
+            let child_ppn = M::entry_get_ppn(entry);
+            let child_level = M::visit_levels_from(level)[1];
+            self.protect_in_table(child_ppn, child_level, start..end, flags)?;
+        }
+        Ok(())
+    }
+    // 计算叶子页表项新的设置。可写的共享页帧必须先复制，因此去掉W位，换成写时复制位；共享内存的页除外
+    fn protected_flags(&self, vpn: VirtPageNum, old_flags: Sv39Flags, flags: Sv39Flags) -> Sv39Flags {
+        let permissions = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::U | Sv39Flags::COW;
+        let mut new_flags = (old_flags - permissions) | flags;
+        let shared = match self.leaf_frames.get(&vpn) {
+            Some(frame_box) => frame_box.ref_count() > 1 && !old_flags.contains(Sv39Flags::SHARED),
+            None => false,
+        };
+        if new_flags.contains(Sv39Flags::W) && (old_flags.contains(Sv39Flags::COW) || shared) {
+            new_flags.remove(Sv39Flags::W);
+            new_flags.insert(Sv39Flags::COW);
+        }
+        new_flags
+    }
+    // 修改区间内预留页的权限；和区间部分重叠的预留区间将被拆分
+    fn protect_lazy(&mut self, vpn_range: Range<VirtPageNum>, flags: Sv39Flags) {
+        let overlapped: Vec<_> = self.lazy_areas.range(..vpn_range.end)
+            .filter(|(_, (end, _))| end.0 > vpn_range.start.0)
+            .map(|(&start, _)| start).collect();
+        for start in overlapped {
+            let (end, old_flags) = self.lazy_areas.remove(&start).unwrap();
+            let (mid_start, mid_end) = (VirtPageNum(usize::max(start.0, vpn_range.start.0)), VirtPageNum(usize::min(end.0, vpn_range.end.0)));
+            if start < mid_start {
+                self.lazy_areas.insert(start, (mid_start, old_flags));
+            }
+            let permissions = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::U;
+            self.lazy_areas.insert(mid_start, (mid_end, (old_flags - permissions) | flags));
+            if mid_end < end {
+                self.lazy_areas.insert(mid_end, (end, old_flags));
+            }
+        }
+    }
+}
+
+// 共享内存对象：一组填满零的页帧，可以映射到多个地址空间中
+//
+// 对象和每一个映射都持有页帧的一份引用。对象被释放、所有的映射都被取消以后，页帧才被释放
+#[derive(Debug)]
+pub struct SharedMemory<A: FrameAllocator = DefaultFrameAllocator> {
+    frames: Vec<FrameBox<A>>,
+}
+
+impl<A: FrameAllocator + Clone> SharedMemory<A> {
+    // 分配n个页帧，创建共享内存对象；页帧数组本身分配失败时也返回错误，不会触发内核堆的分配错误处理
+    pub fn try_new_in<M: PageMode>(n: usize, frame_alloc: A) -> Result<Self, FrameAllocError> {
+        let mut frames = Vec::new();
+        frames.try_reserve_exact(n).map_err(|_| FrameAllocError)?;
+        for _ in 0..n {
+            let frame_box = FrameBox::try_new_in(frame_alloc.clone())?;
+            unsafe { zero_frame::<M, _>(&frame_alloc, frame_box.phys_page_num()) };
+            frames.push(frame_box);
+        }
+        Ok(SharedMemory { frames })
+    }
+    // 共享内存包含的页数
+    pub fn page_count(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl<M: PageMode<Flags = Sv39Flags>, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 把共享内存映射到从vpn开始的区间。映射的页标记为共享，复制地址空间时不使用写时复制
+    pub fn map_shared(&mut self, vpn: VirtPageNum, shared: &SharedMemory<A>, flags: Sv39Flags) -> Result<(), FrameAllocError> {
+        for (i, frame_box) in shared.frames.iter().enumerate() {
+            let cur = VirtPageNum(vpn.0 + i);
+            if let Err(e) = self.allocate_map(cur, frame_box.phys_page_num(), 1, flags | Sv39Flags::SHARED) {
+                if i > 0 { // 撤销已经映射的页
+                    self.unmap(vpn, i).expect("unmap 4K pages");
+                }
+                return Err(e)
+            }
+            self.leaf_frames.insert(cur, frame_box.share());
+        }
+        Ok(())
+    }
+}
+
+// 访问用户内存失败的原因
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UserAccessError {
+    // 地址没有映射，或者用户没有相应的访问权限
+    Fault(VirtAddr),
+    // 分配预留的页或者复制写时复制的页时，没有足够的页帧
+    OutOfMemory,
+}
+
+// 访问用户内存需要检查页表项的U、R、W位和写时复制位
+//
+// 内核通过线性映射访问用户的页帧，不需要设置sstatus的SUM位，也不要求这个地址空间是当前的地址空间
+impl<M: PageMode<Flags = Sv39Flags>, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 把用户地址src开始的内容复制到dst中。缓冲区可以跨过多个页，预留的页将被分配；
+    // 如果其中有用户不能读的页，返回错误，dst中可能已经复制了一部分
+    pub fn copy_from_user(&mut self, src: VirtAddr, dst: &mut [u8]) -> Result<(), UserAccessError> {
+        let mut done = 0;
+        while done < dst.len() {
+            let (pa, n) = self.user_chunk(src, done, dst.len(), false)?;
+            let from = self.frame_alloc.phys_to_virt(pa).0 as *const u8;
+            unsafe { core::ptr::copy_nonoverlapping(from, dst[done..].as_mut_ptr(), n) };
+            done += n;
+        }
+        Ok(())
+    }
+    // 把src复制到用户地址dst开始的内存中。写时复制的页将被复制，预留的页将被分配；
+    // 如果其中有用户不能写的页，返回错误，用户内存中可能已经写入了一部分
+    #[cfg(test)]
+    pub fn copy_to_user(&mut self, dst: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+        let mut done = 0;
+        while done < src.len() {
+            let (pa, n) = self.user_chunk(dst, done, src.len(), true)?;
+            let to = self.frame_alloc.phys_to_virt(pa).0 as *mut u8;
+            unsafe { core::ptr::copy_nonoverlapping(src[done..].as_ptr(), to, n) };
+            done += n;
+        }
+        Ok(())
+    }
+    // 缓冲区从base开始，长度为len，已经复制了done字节；返回下一段的物理地址和不跨过页的长度
+    fn user_chunk(&mut self, base: VirtAddr, done: usize, len: usize, write: bool) -> Result<(PhysAddr, usize), UserAccessError> {
+        let va = base.0.checked_add(done).filter(|_| base.0.checked_add(len).is_some())
+            .map(VirtAddr).ok_or(UserAccessError::Fault(base))?;
+        let pa = self.user_page(va, write)?;
+        let page_left = (1 << M::FRAME_SIZE_BITS) - va.page_offset::<M>();
+        Ok((pa, usize::min(page_left, len - done)))
+    }
+    // 检查用户是否可以访问va，得到对应的物理地址。必要时分配预留的页，或者复制写时复制的页
+    fn user_page(&mut self, va: VirtAddr, write: bool) -> Result<PhysAddr, UserAccessError> {
+        if self.translate(va).is_none() && !self.resolve_lazy_fault(va).map_err(|_| UserAccessError::OutOfMemory)? {
+            return Err(UserAccessError::Fault(va))
+        }
+        let (_, flags, _) = self.translate(va).unwrap();
+        if !flags.contains(Sv39Flags::U | Sv39Flags::R) {
+            return Err(UserAccessError::Fault(va))
+        }
+        if write && !flags.contains(Sv39Flags::W) && !self.resolve_cow_fault(va).map_err(|_| UserAccessError::OutOfMemory)? {
+            return Err(UserAccessError::Fault(va))
+        }
+        // 写时复制后物理地址可能改变
+        Ok(self.translate(va).unwrap().0)
+    }
+}
+
+// 修改权限需要区域的设置也是Sv39Flags
+impl<M: PageMode<Flags = Sv39Flags>, A: FrameAllocator + Clone> MemorySet<M, A> {
+    // 把共享内存映射为从vpn开始的一段区域
+    pub fn map_shared(&mut self, kind: AreaKind, vpn: VirtPageNum, shared: &SharedMemory<A>, flags: Sv39Flags) -> Result<(), FrameAllocError> {
+        let n = shared.page_count();
+        self.check_free(vpn, n);
+        self.addr_space.map_shared(vpn, shared, flags)?;
+        self.insert_area(kind, vpn, n, flags, AreaBacking::Shared);
+        Ok(())
+    }
+    // 修改从vpn开始n个页的访问权限，区间内区域的设置同时修改；只覆盖了一部分的区域将被拆分。
+    // 如果区间中有不属于任何区域的页，不做任何修改，返回false
+    pub fn protect(&mut self, vpn: VirtPageNum, n: usize, flags: Sv39Flags) -> Result<bool, FrameAllocError> {
+        let vpn_end = VirtPageNum(vpn.0 + n);
+        let mut covered = vpn;
+        for area in self.areas.values() {
+            if area.range.end <= covered || area.range.start >= vpn_end {
+                continue
+            }
+            if area.range.start > covered {
+                break
+            }
+            covered = area.range.end;
+        }
+        if covered < vpn_end {
+            return Ok(false)
+        }
+        self.addr_space.protect(vpn, n, flags)?;
+        self.split_area(vpn);
+        self.split_area(vpn_end);
+        let permissions = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::U;
+        for (_, area) in self.areas.range_mut(vpn..vpn_end) {
+            area.flags = (area.flags - permissions) | (flags & permissions);
+        }
+        Ok(true)
+    }
+    // 如果vpn在某个区域的中间，把这个区域从vpn处拆分成两个区域
+    fn split_area(&mut self, vpn: VirtPageNum) {
+        let start = match self.areas.range(..vpn).next_back() {
+            Some((&start, area)) if vpn < area.range.end => start,
+            _ => return,
+        };
+        let area = self.areas.get_mut(&start).unwrap();
+        let backing = match area.backing {
+            AreaBacking::Direct(ppn) => AreaBacking::Direct(PhysPageNum(ppn.0 + (vpn.0 - start.0))),
+            backing => backing,
+        };
+        let upper = MapArea { kind: area.kind, range: vpn..area.range.end, flags: area.flags, backing };
+        area.range.end = vpn;
+        self.areas.insert(vpn, upper);
+    }
+}
+
+// 内存区域的用途
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AreaKind {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+    Stack,
+    Mmap,
+    // 物理内存的线性映射
+    Linear,
+    // 栈下方的保护页，不映射任何页帧；栈溢出时访问它将产生缺页异常
+    Guard,
+}
+
+// 内存区域的页帧从哪里来
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AreaBacking {
+    // 映射到从这个物理页号开始的一段物理内存，比如恒等映射和线性映射
+    Direct(PhysPageNum),
+    // 匿名内存，第一次访问时才分配清零的页帧
+    Anonymous,
+    // 创建区域时就分配清零的页帧，页帧归这个区域所有
+    Owned,
+    // 创建区域时就分配页帧，从ELF文件中复制数据，其余部分清零
+    Copied,
+    // 不映射任何页帧，比如保护页
+    Unmapped,
+    // 映射共享内存对象的页帧，和其它地址空间共享
+    Shared,
+}
+
+// 地址空间中的一段内存区域，记录这段区间为什么被映射
+#[derive(Clone, Debug)]
+pub struct MapArea<F> {
+    pub kind: AreaKind,
+    pub range: Range<VirtPageNum>,
+    pub flags: F,
+    pub backing: AreaBacking,
+}
+
+// 内存集合：分页地址空间，以及其中所有的内存区域。添加、删除和扩大区域时，同时修改页表
+//
+// 区域之间不能重叠；区域的边界不需要按大页对齐，直接映射的区域仍然会尽量使用大页
+#[derive(Debug)]
+pub struct MemorySet<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
+    addr_space: PagedAddrSpace<M, A>,
+    // 所有的内存区域，按开始的虚拟页号索引
+    areas: BTreeMap<VirtPageNum, MapArea<M::Flags>>,
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> MemorySet<M, A> {
+    // 创建一个没有任何区域的内存集合
+    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
+        let addr_space = PagedAddrSpace::try_new_in(page_mode, frame_alloc)?;
+        Ok(MemorySet { addr_space, areas: BTreeMap::new() })
+    }
+    // 创建一个共享kernel全局映射的内存集合，内核的区域不被记录在新的内存集合中。
+    //
+    // 调用者需要保证kernel比新的内存集合活得更久
+    pub unsafe fn try_new_with_global(kernel: &Self) -> Result<Self, FrameAllocError> {
+        let addr_space = PagedAddrSpace::try_new_with_global(&kernel.addr_space)?;
+        Ok(MemorySet { addr_space, areas: BTreeMap::new() })
+    }
+    // 得到下层的分页地址空间
+    #[cfg(test)]
+    pub fn addr_space(&self) -> &PagedAddrSpace<M, A> {
+        &self.addr_space
+    }
+    // 得到下层的分页地址空间，用于切换地址空间和处理缺页异常。不要直接修改其中的映射，否则区域的记录将不准确
+    pub fn addr_space_mut(&mut self) -> &mut PagedAddrSpace<M, A> {
+        &mut self.addr_space
+    }
+    // 添加一段直接映射到物理页号ppn开始的区域
+    pub fn map_direct(&mut self, kind: AreaKind, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        self.check_free(vpn, n);
+        self.addr_space.allocate_map(vpn, ppn, n, flags.clone())?;
+        self.insert_area(kind, vpn, n, flags, AreaBacking::Direct(ppn));
+        Ok(())
+    }
+    // 添加一段区域，立即分配填满零的页帧。区域被删除时，页帧一起释放
+    pub fn map_owned(&mut self, kind: AreaKind, vpn: VirtPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        self.check_free(vpn, n);
+        self.addr_space.allocate_map_owned(vpn, n, flags.clone())?;
+        self.insert_area(kind, vpn, n, flags, AreaBacking::Owned);
+        Ok(())
+    }
+    // 添加一段从vpn开始n个页的匿名内存栈，并在栈的下方留出一个保护页
+    pub fn map_stack(&mut self, vpn: VirtPageNum, n: usize, flags: M::Flags) {
+        let guard_vpn = VirtPageNum(vpn.0.checked_sub(1).expect("no room for guard page below stack"));
+        self.check_free(guard_vpn, n + 1);
+        self.addr_space.reserve_lazy(vpn, n, flags.clone());
+        self.insert_area(AreaKind::Guard, guard_vpn, 1, flags.clone(), AreaBacking::Unmapped);
+        self.insert_area(AreaKind::Stack, vpn, n, flags, AreaBacking::Anonymous);
+    }
+    // 添加一段直接映射到物理页号ppn开始的栈，比如内核的启动栈，并在栈的下方留出一个保护页。
+    // 保护页对应的物理页不被映射
+    pub fn map_stack_direct(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        let guard_vpn = VirtPageNum(vpn.0.checked_sub(1).expect("no room for guard page below stack"));
+        self.check_free(guard_vpn, n + 1);
+        self.addr_space.allocate_map(vpn, ppn, n, flags.clone())?;
+        self.insert_area(AreaKind::Guard, guard_vpn, 1, flags.clone(), AreaBacking::Unmapped);
+        self.insert_area(AreaKind::Stack, vpn, n, flags, AreaBacking::Direct(ppn));
+        Ok(())
+    }
+    // 添加一段区域，把data复制到区域中从offset字节开始的位置，其余部分清零。用于加载ELF文件的段
+    pub fn map_copied(&mut self, kind: AreaKind, vpn: VirtPageNum, n: usize, flags: M::Flags, data: &[u8], offset: usize) -> Result<(), FrameAllocError> {
+        let page_size = 1 << M::FRAME_SIZE_BITS;
+        if offset + data.len() > n * page_size {
+            panic!("data of {} bytes at offset {:#x} exceeds area of {} pages", data.len(), offset, n)
+        }
+        self.check_free(vpn, n);
+        self.addr_space.allocate_map_owned(vpn, n, flags.clone())?;
+        for i in 0..n {
+            // 这一页中需要复制的部分
+            let (page_start, page_end) = (i * page_size, (i + 1) * page_size);
+            let (start, end) = (usize::max(offset, page_start), usize::min(offset + data.len(), page_end));
+            if start < end {
+                let ppn = self.addr_space.leaf_frames[&VirtPageNum(vpn.0 + i)].phys_page_num();
+                let dst = self.addr_space.frame_alloc.phys_to_virt(ppn.addr_begin::<M>()).0 + (start - page_start);
+                unsafe { core::ptr::copy_nonoverlapping(data[start - offset..].as_ptr(), dst as *mut u8, end - start) };
+            }
+        }
+        self.insert_area(kind, vpn, n, flags, AreaBacking::Copied);
+        Ok(())
+    }
+    // 删除包含va的区域，取消它的所有映射并释放它拥有的页帧；返回被删除的区域
+    pub fn remove_area(&mut self, va: VirtAddr) -> Result<Option<MapArea<M::Flags>>, FrameAllocError> {
+        let start = match self.find_area(va) {
+            Some(area) => area.range.start,
+            None => return Ok(None),
+        };
+        let area = self.areas.remove(&start).unwrap();
+        self.addr_space.unmap(area.range.start, area.range.end.0 - area.range.start.0)?;
+        if area.kind == AreaKind::Stack {
+            self.remove_guard_below(area.range.start);
+        }
+        Ok(Some(area))
+    }
+    // 把包含va的区域扩大n个页。栈向低地址扩大，其它区域向高地址扩大；
+    // 新增的部分中，直接映射的区域继续映射相邻的物理内存，立即分配页帧的区域同样立即分配，其它区域作为匿名内存
+    #[cfg(test)]
+    pub fn grow_area(&mut self, va: VirtAddr, n: usize) -> Result<(), FrameAllocError> {
+        let (start, grow_vpn) = match self.find_area(va) {
+            Some(area) if area.kind == AreaKind::Stack => {
+                let grow_vpn = area.range.start.0.checked_sub(n).expect("grow stack below address zero");
+                (area.range.start, VirtPageNum(grow_vpn))
+            },
+            Some(area) => (area.range.start, area.range.end),
+            None => panic!("no area at address {:#x}", va.0),
+        };
+        // 栈的保护页跟着栈向下移动
+        let guard = if grow_vpn < start { self.remove_guard_below(start) } else { None };
+        if let Some(guard) = &guard {
+            self.check_free(VirtPageNum(grow_vpn.0.checked_sub(1).expect("no room for guard page below stack")), n + 1);
+            self.insert_area(AreaKind::Guard, VirtPageNum(grow_vpn.0 - 1), 1, guard.flags.clone(), AreaBacking::Unmapped);
+        } else {
+            self.check_free(grow_vpn, n);
+        }
+        let mut area = self.areas.remove(&start).unwrap();
+        let result = match area.backing {
+            AreaBacking::Direct(ppn) => {
+                // 新增的部分和原来的部分保持相同的虚拟页号到物理页号的偏移
+                let grow_ppn = PhysPageNum(ppn.0.wrapping_add(grow_vpn.0.wrapping_sub(start.0)));
+                self.addr_space.allocate_map(grow_vpn, grow_ppn, n, area.flags.clone())
+            },
+            AreaBacking::Owned => self.addr_space.allocate_map_owned(grow_vpn, n, area.flags.clone()),
+            AreaBacking::Anonymous | AreaBacking::Copied => {
+                self.addr_space.reserve_lazy(grow_vpn, n, area.flags.clone());
+                Ok(())
+            },
+            AreaBacking::Unmapped => Ok(()),
+            AreaBacking::Shared => panic!("shared memory area cannot grow"),
+        };
+        if result.is_ok() {
+            if grow_vpn < start {
+                if let AreaBacking::Direct(ppn) = area.backing {
+                    area.backing = AreaBacking::Direct(PhysPageNum(ppn.0 - n));
+                }
+                area.range.start = grow_vpn;
+            } else {
+                area.range.end = VirtPageNum(area.range.end.0 + n);
+            }
+        } else if let Some(guard) = guard { // 恢复原来的保护页
+            self.areas.remove(&VirtPageNum(grow_vpn.0 - 1));
+            self.areas.insert(guard.range.start, guard);
+        }
+        self.areas.insert(area.range.start, area);
+        result
+    }
+    // 访问va是否碰到了栈的保护页，即栈是否溢出
+    pub fn is_guard(&self, va: VirtAddr) -> bool {
+        matches!(self.find_area(va), Some(area) if area.kind == AreaKind::Guard)
+    }
+    // 查找包含va的区域
+    pub fn find_area(&self, va: VirtAddr) -> Option<&MapArea<M::Flags>> {
+        let vpn = va.page_number::<M>();
+        match self.areas.range(..=vpn).next_back() {
+            Some((_, area)) if vpn < area.range.end => Some(area),
+            _ => None,
+        }
+    }
+    // 按地址从低到高遍历所有的区域
+    pub fn areas(&self) -> impl Iterator<Item = &MapArea<M::Flags>> {
+        self.areas.values()
+    }
+    // 删除紧挨在vpn下方的保护页
+    fn remove_guard_below(&mut self, vpn: VirtPageNum) -> Option<MapArea<M::Flags>> {
+        let start = match self.areas.range(..vpn).next_back() {
+            Some((&start, area)) if area.kind == AreaKind::Guard && area.range.end == vpn => start,
+            _ => return None,
+        };
+        self.areas.remove(&start)
+    }
+    // 从vpn开始的n个页是否不属于任何区域
+    pub fn is_free(&self, vpn: VirtPageNum, n: usize) -> bool {
+        self.overlapped_area(vpn, n).is_none()
+    }
+    fn overlapped_area(&self, vpn: VirtPageNum, n: usize) -> Option<&MapArea<M::Flags>> {
+        match self.areas.range(..VirtPageNum(vpn.0 + n)).next_back() {
+            Some((_, area)) if area.range.end > vpn => Some(area),
+            _ => None,
+        }
+    }
+    // 新的区域不能和已有的区域重叠
+    fn check_free(&self, vpn: VirtPageNum, n: usize) {
+        if let Some(area) = self.overlapped_area(vpn, n) {
+            panic!("area [{:#x}, {:#x}) overlaps with {:?} area", vpn.0, vpn.0 + n, area.kind)
+        }
+    }
+    fn insert_area(&mut self, kind: AreaKind, vpn: VirtPageNum, n: usize, flags: M::Flags, backing: AreaBacking) {
+        let range = vpn..VirtPageNum(vpn.0 + n);
+        self.areas.insert(vpn, MapArea { kind, range, flags, backing });
+    }
+}
+
+#[derive(Debug)]
+pub struct MapPairs<M> {
+    ans_iter: alloc::vec::IntoIter<(PageLevel, Range<VirtPageNum>)>,
+    _mode: core::marker::PhantomData<M>,
+}
+
+impl<M: PageMode> MapPairs<M> {
+    pub fn solve(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, _mode: M) -> Self {
+        let mut ans = Vec::new();
+        for &i in M::visit_levels_until(PageLevel::leaf_level()) {
+            let align = M::get_layout_for_level(i).frame_align();
+            if vpn.0.wrapping_sub(ppn.0) % align != 0 || n < align {
+                continue;
+            }
+            let (mut ve_prev, mut vs_prev) = (None, None);
+            for &j in M::visit_levels_from(i) {
+                let align_cur = M::get_layout_for_level(j).frame_align();
+                let ve_cur = align_cur * ((vpn.0 + align_cur - 1) / align_cur); // a * roundup(v / a)
+                let vs_cur = align_cur * ((vpn.0 + n) / align_cur); // a * rounddown((v+n) / a)
+                if let (Some(ve_prev), Some(vs_prev)) = (ve_prev, vs_prev) {
+                    if ve_cur != ve_prev {
+                        ans.push((j, VirtPageNum(ve_cur)..VirtPageNum(ve_prev)));
+                    }
+                    if vs_prev != vs_cur {
+                        ans.push((j, VirtPageNum(vs_prev)..VirtPageNum(vs_cur)));
                     }
                 } else {
                     if ve_cur != vs_cur { 
@@ -661,7 +2627,7 @@ impl<M: PageMode> MapPairs<M> {
             break;
         } 
         // println!("[SOLVE] Ans = {:x?}", ans);
-        Self { ans_iter: ans.into_iter(), mode }
+        Self { ans_iter: ans.into_iter(), _mode: core::marker::PhantomData }
     }
 }
 
@@ -672,29 +2638,125 @@ impl<M> Iterator for MapPairs<M> {
     }
 }
 
+#[cfg(any(test, feature = "self-test"))]
 pub(crate) fn test_map_solve() {
-    let pairs = MapPairs::solve(VirtPageNum(0x90_000), PhysPageNum(0x50_000), 666666, Sv39).collect::<Vec<_>>();
-    assert_eq!(pairs, [
-        (PageLevel(2), VirtPageNum(786432)..VirtPageNum(1048576)), 
-        (PageLevel(1), VirtPageNum(589824)..VirtPageNum(786432)), 
-        (PageLevel(1), VirtPageNum(1048576)..VirtPageNum(1256448)), 
-        (PageLevel(0), VirtPageNum(1256448)..VirtPageNum(1256490))
-    ]);
-    let pairs = MapPairs::solve(VirtPageNum(0x90_001), PhysPageNum(0x50_001), 77777, Sv39).collect::<Vec<_>>();
-    assert_eq!(pairs, [
-        (PageLevel(1), VirtPageNum(590336)..VirtPageNum(667136)), 
-        (PageLevel(0), VirtPageNum(589825)..VirtPageNum(590336)), 
-        (PageLevel(0), VirtPageNum(667136)..VirtPageNum(667602))
-    ]);
+    #[cfg(target_pointer_width = "64")] {
+        let pairs = MapPairs::solve(VirtPageNum(0x90_000), PhysPageNum(0x50_000), 666666, Sv39).collect::<Vec<_>>();
+        assert_eq!(pairs, [
+            (PageLevel(2), VirtPageNum(786432)..VirtPageNum(1048576)), 
+            (PageLevel(1), VirtPageNum(589824)..VirtPageNum(786432)), 
+            (PageLevel(1), VirtPageNum(1048576)..VirtPageNum(1256448)), 
+            (PageLevel(0), VirtPageNum(1256448)..VirtPageNum(1256490))
+        ]);
+        let pairs = MapPairs::solve(VirtPageNum(0x90_001), PhysPageNum(0x50_001), 77777, Sv39).collect::<Vec<_>>();
+        assert_eq!(pairs, [
+            (PageLevel(1), VirtPageNum(590336)..VirtPageNum(667136)), 
+            (PageLevel(0), VirtPageNum(589825)..VirtPageNum(590336)), 
+            (PageLevel(0), VirtPageNum(667136)..VirtPageNum(667602))
+        ]);
+        let pairs = MapPairs::solve(VirtPageNum(0x7fff_000), PhysPageNum(0x17fff_000), 0x8000_000 + 0x2005, Sv48).collect::<Vec<_>>();
+        assert_eq!(pairs, [
+            (PageLevel(3), VirtPageNum(0x8000_000)..VirtPageNum(0x10000_000)), 
+            (PageLevel(1), VirtPageNum(0x7fff_000)..VirtPageNum(0x8000_000)), 
+            (PageLevel(1), VirtPageNum(0x10000_000)..VirtPageNum(0x10001_000)), 
+            (PageLevel(0), VirtPageNum(0x10001_000)..VirtPageNum(0x10001_005))
+        ]);
+        let pairs = MapPairs::solve(VirtPageNum(0xf_ffff_fe00), PhysPageNum(0x1f_ffff_fe00), 0x10_0000_0201, Sv57).collect::<Vec<_>>();
+        assert_eq!(pairs, [
+            (PageLevel(4), VirtPageNum(0x10_0000_0000)..VirtPageNum(0x20_0000_0000)), 
+            (PageLevel(1), VirtPageNum(0xf_ffff_fe00)..VirtPageNum(0x10_0000_0000)), 
+            (PageLevel(0), VirtPageNum(0x20_0000_0000)..VirtPageNum(0x20_0000_0001))
+        ]);
+    }
+    #[cfg(any(test, target_pointer_width = "32"))] {
+        let pairs = MapPairs::solve(VirtPageNum(0x80_001), PhysPageNum(0x40_001), 0x1000, Sv32).collect::<Vec<_>>();
+        assert_eq!(pairs, [
+            (PageLevel(1), VirtPageNum(0x80_400)..VirtPageNum(0x81_000)), 
+            (PageLevel(0), VirtPageNum(0x80_001)..VirtPageNum(0x80_400)), 
+            (PageLevel(0), VirtPageNum(0x81_000)..VirtPageNum(0x81_001))
+        ]);
+    }
     println!("[kernel-map-solve] Map solver test passed");
 }
 
+// 主机上的测试在64位的主机上运行，Sv32的页表也在其中测试
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests;
+
 // 切换地址空间，同时需要提供1.地址空间的详细设置 2.地址空间编号
-// 不一定最后的API就是这样的，留个坑
+// 不一定最后的API就是这样的，留个坑。这里不刷新页表缓存，由PagedAddrSpace::activate按编号的代数决定是否刷新
+#[cfg(all(target_pointer_width = "64", not(test)))]
 pub unsafe fn activate_paged_riscv_sv39(root_ppn: PhysPageNum, asid: AddressSpaceId) {
     use riscv::register::satp::{self, Mode};
     satp::set(Mode::Sv39, asid.0 as usize, root_ppn.0);
-    asm!("sfence.vma {}", in(reg) asid.0 as usize);
+}
+
+#[cfg(all(target_pointer_width = "64", not(test)))]
+pub unsafe fn activate_paged_riscv_sv48(root_ppn: PhysPageNum, asid: AddressSpaceId) {
+    use riscv::register::satp::{self, Mode};
+    satp::set(Mode::Sv48, asid.0 as usize, root_ppn.0);
+}
+
+#[cfg(all(target_pointer_width = "64", not(test)))]
+pub unsafe fn activate_paged_riscv_sv57(root_ppn: PhysPageNum, asid: AddressSpaceId) {
+    use riscv::register::satp::{self, Mode};
+    satp::set(Mode::Sv57, asid.0 as usize, root_ppn.0);
+}
+
+#[cfg(all(target_pointer_width = "32", not(test)))]
+pub unsafe fn activate_paged_riscv_sv32(root_ppn: PhysPageNum, asid: AddressSpaceId) {
+    use riscv::register::satp::{self, Mode};
+    satp::set(Mode::Sv32, asid.0 as usize, root_ppn.0);
+}
+
+// 得到切换到这个地址空间时写入satp的值。用户和内核使用相同的分页模式，从当前的satp得到
+#[cfg(not(test))]
+pub fn satp_with_current_mode(root_ppn: PhysPageNum, asid: AddressSpaceId) -> usize {
+    let current = riscv::register::satp::read().bits();
+    #[cfg(target_pointer_width = "64")]
+    return (current & !((1 << 60) - 1)) | ((asid.0 as usize) << 44) | root_ppn.0;
+    #[cfg(target_pointer_width = "32")]
+    return (current & !((1 << 31) - 1)) | ((asid.0 as usize) << 22) | root_ppn.0;
+}
+
+// 探测平台支持的最大分页模式。和max_asid一样，需要通过读写satp寄存器获得
+//
+// 写入不支持的模式时，整个satp的写入操作无效，因此写入后读回模式，就能知道是否支持。
+// 写入的瞬间分页已经开启，所以探测用的页表需要映射当前运行在高地址的内核。
+// 内核所在的0xffffffff80000000，在三种模式下都用一个1G大页映射到0x80000000：
+// Sv39直接使用根页表的第510项；Sv48经过根页表第511项，到下一级页表的第510项；Sv57再多经过一级第511项。
+#[cfg(all(target_pointer_width = "64", not(test)))]
+pub fn probe_page_mode() -> riscv::register::satp::Mode {
+    use riscv::register::satp::{self, Mode};
+    #[repr(C, align(4096))]
+    struct ProbeTable([usize; 512]);
+    static mut PROBE_TABLES: [ProbeTable; 3] = [ProbeTable([0; 512]), ProbeTable([0; 512]), ProbeTable([0; 512])];
+    let flags = (Sv39Flags::V | Sv39Flags::R | Sv39Flags::W | Sv39Flags::X | Sv39Flags::A | Sv39Flags::D).bits() as usize;
+    let ppn_of = |table: &ProbeTable| VirtAddr(table as *const _ as usize).to_phys().page_number::<Sv39>().0;
+    let root_ppn = unsafe {
+        let memory_ppn = PhysAddr(MEMORY_START).page_number::<Sv39>().0;
+        let (t1, t2) = (ppn_of(&PROBE_TABLES[1]), ppn_of(&PROBE_TABLES[2]));
+        PROBE_TABLES[0].0[510] = (memory_ppn << 10) | flags; // Sv39
+        PROBE_TABLES[0].0[511] = (t1 << 10) | Sv39Flags::V.bits() as usize;
+        PROBE_TABLES[1].0[510] = (memory_ppn << 10) | flags; // Sv48
+        PROBE_TABLES[1].0[511] = (t2 << 10) | Sv39Flags::V.bits() as usize;
+        PROBE_TABLES[2].0[510] = (memory_ppn << 10) | flags; // Sv57
+        PhysPageNum(ppn_of(&PROBE_TABLES[0]))
+    };
+    let satp_prev = satp::read().bits();
+    let mut ans = Mode::Bare;
+    for &mode in &[Mode::Sv57, Mode::Sv48, Mode::Sv39] {
+        unsafe { 
+            satp::set(mode, 0, root_ppn.0);
+            let supported = satp::read().mode() == mode;
+            asm!("csrw satp, {}", "sfence.vma", in(reg) satp_prev);
+            if supported {
+                ans = mode;
+                break;
+            }
+        }
+    }
+    ans
 }
 
 // 自身映射地址空间；虚拟地址等于物理地址
@@ -0,0 +1,614 @@
+//! 在主机上运行的mm模块测试
+//!
+//! 用一段主机内存模拟物理内存，页表和页帧都从这段内存中分配。运行`cargo test`即可
+
+use super::*;
+
+// 模拟的物理内存的起始地址和大小，起始地址和qemu相同
+const FAKE_MEMORY_START: usize = MEMORY_START;
+const FAKE_MEMORY_SIZE: usize = 4 * 1024 * 1024;
+const FAKE_PAGE_SIZE: usize = 4096;
+
+// 模拟的物理内存，同时作为页帧分配器
+struct FakeMemory {
+    _ram: Vec<u8>,
+    base: usize, // 第一个页帧在主机内存中的地址，按页对齐
+    frame_alloc: spin::Mutex<BitmapFrameAllocator>,
+}
+
+impl FakeMemory {
+    fn new() -> Self {
+        let mut ram = vec![0u8; FAKE_MEMORY_SIZE + FAKE_PAGE_SIZE];
+        let ptr = ram.as_mut_ptr();
+        let base = ptr as usize + ptr.align_offset(FAKE_PAGE_SIZE);
+        let from = PhysAddr(FAKE_MEMORY_START).page_number::<DefaultPageMode>();
+        let to = PhysAddr(FAKE_MEMORY_START + FAKE_MEMORY_SIZE).page_number::<DefaultPageMode>();
+        FakeMemory { _ram: ram, base, frame_alloc: spin::Mutex::new(BitmapFrameAllocator::new(from, to)) }
+    }
+    fn free_frames(&self) -> usize {
+        self.frame_alloc.lock().stats().free_frames
+    }
+}
+
+impl PhysMemory for FakeMemory {
+    fn phys_to_virt(&self, pa: PhysAddr) -> VirtAddr {
+        assert!((FAKE_MEMORY_START..FAKE_MEMORY_START + FAKE_MEMORY_SIZE).contains(&pa.0), 
+            "physical address {:#x} out of fake memory", pa.0);
+        VirtAddr(self.base + (pa.0 - FAKE_MEMORY_START))
+    }
+}
+
+impl FrameAllocator for FakeMemory {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.frame_alloc.allocate_frame()
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.frame_alloc.deallocate_frame(ppn)
+    }
+    fn share_frame(&self, ppn: PhysPageNum) {
+        self.frame_alloc.share_frame(ppn)
+    }
+    fn frame_ref_count(&self, ppn: PhysPageNum) -> usize {
+        self.frame_alloc.frame_ref_count(ppn)
+    }
+}
+
+// 伪随机数生成器；固定种子，测试失败时可以复现
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+    // 随机的一段映射：虚拟页号和物理页号的差按随机的一级大页对齐
+    fn next_mapping<M: PageMode>(&mut self, max_level: usize, max_pages: usize) -> (VirtPageNum, PhysPageNum, usize) {
+        let align = M::get_layout_for_level(PageLevel((self.next() % (max_level + 1)) as u8)).frame_align();
+        let vpn = 1 + self.next() % 0xff_ffff;
+        let ppn = vpn % align + (self.next() % 16) * align;
+        (VirtPageNum(vpn), PhysPageNum(ppn), 1 + self.next() % max_pages)
+    }
+}
+
+// 逐页计算每一页应当使用的页表等级：在物理页号对齐、并且整个大页都在区间内的等级中，选择最大的一级
+fn brute_force_levels<M: PageMode>(vpn: VirtPageNum, ppn: PhysPageNum, n: usize) -> Vec<u8> {
+    (vpn.0..vpn.0 + n).map(|v| {
+        let level = M::visit_levels_until(PageLevel::leaf_level()).iter().find(|&&level| {
+            let align = M::get_layout_for_level(level).frame_align();
+            let block = v / align * align;
+            vpn.0.wrapping_sub(ppn.0) % align == 0 && block >= vpn.0 && block + align <= vpn.0 + n
+        });
+        level.expect("leaf level always fits").0
+    }).collect()
+}
+
+// 把MapPairs::solve的结果展开为每一页的页表等级，同时检查区间互不重叠、按等级对齐，且恰好覆盖整个区间
+fn solved_levels<M: PageMode>(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, mode: M) -> Vec<u8> {
+    let mut levels = vec![None; n];
+    for (level, range) in MapPairs::solve(vpn, ppn, n, mode) {
+        let align = M::get_layout_for_level(level).frame_align();
+        assert!(range.start < range.end, "empty range {:x?}", range);
+        assert!(range.start.0 % align == 0 && range.end.0 % align == 0, "range {:x?} not aligned to level {:?}", range, level);
+        assert!(range.start.0 >= vpn.0 && range.end.0 <= vpn.0 + n, "range {:x?} out of mapping", range);
+        for v in range.start.0..range.end.0 {
+            let slot = &mut levels[v - vpn.0];
+            assert!(slot.is_none(), "page {:#x} solved twice", v);
+            *slot = Some(level.0);
+        }
+    }
+    levels.into_iter().enumerate()
+        .map(|(i, level)| level.unwrap_or_else(|| panic!("page {:#x} not solved", vpn.0 + i)))
+        .collect()
+}
+
+fn check_solve<M: PageMode>(mode: M, rng: &mut XorShift, max_level: usize, max_pages: usize) {
+    for _ in 0..64 {
+        let (vpn, ppn, n) = rng.next_mapping::<M>(max_level, max_pages);
+        assert_eq!(solved_levels(vpn, ppn, n, mode), brute_force_levels::<M>(vpn, ppn, n),
+            "solve vpn = {:#x}, ppn = {:#x}, n = {:#x}", vpn.0, ppn.0, n);
+    }
+}
+
+#[test]
+fn map_solve() {
+    test_map_solve();
+}
+
+#[test]
+fn map_solve_matches_brute_force() {
+    let mut rng = XorShift(0x2021_1003);
+    check_solve(Sv39, &mut rng, 2, 0x90_000);
+    check_solve(Sv48, &mut rng, 2, 0x90_000);
+    check_solve(Sv57, &mut rng, 2, 0x90_000);
+    check_solve(Sv32, &mut rng, 1, 0x2_000);
+}
+
+#[test]
+fn allocate_map_matches_page_by_page_mapping() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    let mut rng = XorShift(0x8020_0000);
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    for _ in 0..16 {
+        let (vpn, ppn, n) = rng.next_mapping::<Sv39>(1, 0x1000);
+        let mut solved = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+        solved.allocate_map(vpn, ppn, n, flags).expect("map at once");
+        let mut by_page = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+        for i in 0..n {
+            by_page.allocate_map(VirtPageNum(vpn.0 + i), PhysPageNum(ppn.0 + i), 1, flags).expect("map one page");
+        }
+        let levels = brute_force_levels::<Sv39>(vpn, ppn, n);
+        for (i, &expected_level) in levels.iter().enumerate() {
+            let va = VirtAddr(VirtPageNum(vpn.0 + i).addr_begin::<Sv39>().0 + rng.next() % FAKE_PAGE_SIZE);
+            let (pa, page_flags, level) = solved.translate(va).expect("page mapped at once");
+            let (expected_pa, expected_flags, _) = by_page.translate(va).expect("page mapped page by page");
+            assert_eq!((pa, page_flags), (expected_pa, expected_flags), "translate {:#x}", va.0);
+            assert_eq!(level.0, expected_level, "page level of {:#x}", va.0);
+        }
+        assert_eq!(solved.translate(VirtPageNum(vpn.0 - 1).addr_begin::<Sv39>()), None, "page before mapping");
+        assert_eq!(solved.translate(VirtPageNum(vpn.0 + n).addr_begin::<Sv39>()), None, "page after mapping");
+    }
+    assert_eq!(memory.free_frames(), total_frames, "page tables freed with address spaces");
+}
+
+#[test]
+fn frame_alloc() {
+    test_frame_alloc();
+    test_bitmap_frame_alloc();
+}
+
+#[test]
+fn asid_alloc() {
+    test_asid_alloc();
+    test_asid_manager();
+}
+
+#[test]
+fn asid_dealloc_max() {
+    let max_asid = AddressSpaceId(2);
+    let mut alloc = StackAsidAllocator::new(max_asid);
+    let asids: Vec<_> = (0..3).map(|_| alloc.allocate_asid().expect("allocate asid")).collect();
+    assert_eq!(asids, [AddressSpaceId(0), AddressSpaceId(1), max_asid], "allocate all asids");
+    alloc.deallocate_asid(max_asid);
+    assert_eq!(alloc.allocate_asid(), Ok(max_asid), "reuse max asid");
+    assert_eq!(alloc.allocate_asid(), Err(AsidAllocError), "no asid remains");
+    // 硬件不支持地址空间编号时，唯一的编号0也是最大编号
+    let mut alloc = StackAsidAllocator::new(DEFAULT_ASID);
+    assert_eq!(alloc.allocate_asid(), Ok(DEFAULT_ASID), "asid not implemented, allocate");
+    alloc.deallocate_asid(DEFAULT_ASID);
+    assert_eq!(alloc.allocate_asid(), Ok(DEFAULT_ASID), "asid not implemented, reuse");
+}
+
+#[test]
+#[should_panic(expected = "has not been allocated")]
+fn asid_dealloc_twice() {
+    let mut alloc = StackAsidAllocator::new(DEFAULT_ASID);
+    alloc.allocate_asid().expect("allocate asid");
+    alloc.deallocate_asid(DEFAULT_ASID);
+    alloc.deallocate_asid(DEFAULT_ASID);
+}
+
+// 模拟切换地址空间，记录最后一次写入satp的根页表和地址空间编号
+static ACTIVATED: spin::Mutex<Option<(PhysPageNum, AddressSpaceId)>> = spin::Mutex::new(None);
+
+unsafe fn fake_activate(root_ppn: PhysPageNum, asid: AddressSpaceId) {
+    *ACTIVATED.lock() = Some((root_ppn, asid));
+}
+
+fn fake_satp(root_ppn: PhysPageNum, asid: AddressSpaceId) -> usize {
+    ((asid.0 as usize) << 44) | root_ppn.0
+}
+
+#[test]
+fn activate_address_space() {
+    // 只有这个测试使用全局的ASID_MANAGER；和内核启动时一样，先设置最大编号
+    ASID_MANAGER.lock().set_max_asid(AddressSpaceId(1));
+    let memory = FakeMemory::new();
+    let mut a = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space a");
+    let mut b = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space b");
+    let mut c = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space c");
+    unsafe { a.activate(fake_activate) };
+    assert_eq!(*ACTIVATED.lock(), Some((a.root_page_number(), AddressSpaceId(0))), "activate a");
+    let satp = unsafe { b.prepare_activate(fake_satp) };
+    assert_eq!(satp, (1 << 44) | b.root_page_number().0, "satp of b");
+    // 编号用完，回绕到新的一代，a和b的编号作废
+    let satp = unsafe { c.prepare_activate(fake_satp) };
+    assert_eq!(satp, c.root_page_number().0, "c gets asid 0 in new generation");
+    unsafe { a.activate(fake_activate) };
+    assert_eq!(*ACTIVATED.lock(), Some((a.root_page_number(), AddressSpaceId(1))), "a gets new asid in new generation");
+}
+
+#[test]
+fn asid_manager_without_asid() {
+    // 地址空间在当前代数中被释放，编号被回收，下一个地址空间不需要回绕
+    let mut manager = AsidManager::new(DEFAULT_ASID);
+    let (t1, _) = manager.activate(None);
+    assert!(manager.deallocate(t1), "deallocate the only asid");
+    let (t2, flush) = manager.activate(None);
+    assert_eq!((t2.asid(), flush), (DEFAULT_ASID, false), "reuse the only asid without rollover");
+    let (t3, flush) = manager.activate(None);
+    assert_eq!((t3.asid(), flush), (DEFAULT_ASID, true), "rollover for another address space");
+    assert!(!manager.deallocate(t2), "asid from old generation");
+    assert!(manager.deallocate(t3), "deallocate asid in new generation");
+}
+
+#[test]
+fn unmap() {
+    let memory = FakeMemory::new();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    // 映射包含1G、2M和4K页的区间，再取消中间的一部分映射，需要拆分大页
+    space.allocate_map(VirtPageNum(0x40_000), PhysPageNum(0x0), 0x40_000 + 0x400 + 3, Sv39Flags::R | Sv39Flags::W)
+        .expect("map huge pages");
+    space.unmap(VirtPageNum(0x40_1ff), 0x202).expect("unmap part of huge pages");
+    assert!(!space.frames.is_empty(), "split huge pages");
+    space.unmap(VirtPageNum(0x40_000), 0x40_000 + 0x400 + 3).expect("unmap all");
+    assert!(space.frames.is_empty(), "all intermediate page tables freed");
+    let root = unsafe { unref_ppn_mut::<Sv39, _>(&memory, space.root_page_number()) };
+    assert!(Sv39::page_table_is_empty(root), "root page table is empty");
+}
+
+#[test]
+fn translate() {
+    let memory = FakeMemory::new();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    space.allocate_map(VirtPageNum(0x40_000), PhysPageNum(0x0), 0x40_000 + 0x3, flags)
+        .expect("map huge pages");
+    let ans = space.translate(VirtAddr(0x4000_1234));
+    assert_eq!(ans, Some((PhysAddr(0x1234), flags | Sv39Flags::V, PageLevel(2))), "translate in 1G page");
+    let ans = space.translate(VirtAddr(0x8000_1234));
+    assert_eq!(ans, Some((PhysAddr(0x4000_1234), flags | Sv39Flags::V, PageLevel(0))), "translate in 4K page");
+    assert_eq!(space.translate(VirtAddr(0x8000_3000)), None, "translate unmapped address");
+    space.unmap(VirtPageNum(0x40_001), 1).expect("unmap one page in huge page");
+    assert_eq!(space.translate(VirtAddr(0x4000_1234)), None, "translate unmapped page in split huge page");
+    let ans = space.translate(VirtAddr(0x4020_0000));
+    assert_eq!(ans, Some((PhysAddr(0x20_0000), flags | Sv39Flags::V, PageLevel(1))), "translate in split 2M page");
+    let levels: Vec<_> = space.walk(VirtPageNum(0x0), 0x100_000).map(|(_, _, _, level)| level.0).collect();
+    let mut expected = Vec::new();
+    expected.push(0); // 0x40_000
+    expected.extend(core::iter::repeat(0).take(510)); // 0x40_002..0x40_200
+    expected.extend(core::iter::repeat(1).take(511)); // 0x40_200..0x80_000
+    expected.extend(core::iter::repeat(0).take(3)); // 0x80_000..0x80_003
+    assert_eq!(levels, expected, "walk through all leaf entries");
+}
+
+#[test]
+fn fork_cow() {
+    let memory = FakeMemory::new();
+    let mut parent = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let frame_box = FrameBox::try_new_in(&memory).expect("allocate user frame");
+    let ppn = frame_box.phys_page_num();
+    unsafe { *(memory.phys_to_virt(ppn.addr_begin::<Sv39>()).0 as *mut u8) = 0x66 };
+    let flags = Sv39Flags::U | Sv39Flags::R | Sv39Flags::W;
+    parent.allocate_map(VirtPageNum(0x1000), ppn, 1, flags).expect("map user page");
+    parent.leaf_frames.insert(VirtPageNum(0x1000), frame_box);
+    parent.allocate_map(VirtPageNum(0x1001), PhysPageNum(0x0), 1, Sv39Flags::U | Sv39Flags::R).expect("map read only page");
+    let mut child = parent.fork_cow().expect("fork address space");
+    let cow_flags = Sv39Flags::V | Sv39Flags::U | Sv39Flags::R | Sv39Flags::COW;
+    let va = VirtAddr(0x100_0000);
+    assert_eq!(parent.translate(va), Some((ppn.addr_begin::<Sv39>(), cow_flags, PageLevel(0))), "parent page is copy-on-write");
+    assert_eq!(child.translate(va), Some((ppn.addr_begin::<Sv39>(), cow_flags, PageLevel(0))), "child page is copy-on-write");
+    let (_, ro_flags, _) = child.translate(VirtAddr(0x100_1000)).expect("read only page");
+    assert!(!ro_flags.contains(Sv39Flags::COW), "read only page is shared as is");
+    assert_eq!(child.resolve_cow_fault(VirtAddr(0x100_1000)), Ok(false), "write to read only page");
+    assert_eq!(child.resolve_cow_fault(VirtAddr(0x200_0000)), Ok(false), "write to unmapped page");
+    // 子地址空间写入，页帧被共享，需要复制
+    assert_eq!(child.resolve_cow_fault(va), Ok(true), "resolve child write");
+    let (child_pa, child_flags, _) = child.translate(va).expect("child page");
+    assert_ne!(child_pa, ppn.addr_begin::<Sv39>(), "child page copied");
+    assert_eq!(child_flags, Sv39Flags::V | flags, "child page writable");
+    assert_eq!(unsafe { *(memory.phys_to_virt(child_pa).0 as *const u8) }, 0x66, "child page content copied");
+    // 父地址空间写入，页帧已经只被自己拥有，直接恢复写权限
+    assert_eq!(parent.resolve_cow_fault(va), Ok(true), "resolve parent write");
+    assert_eq!(parent.translate(va), Some((ppn.addr_begin::<Sv39>(), Sv39Flags::V | flags, PageLevel(0))), "parent page writable in place");
+}
+
+#[test]
+fn lazy_alloc() {
+    let memory = FakeMemory::new();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let flags = Sv39Flags::U | Sv39Flags::R | Sv39Flags::W;
+    space.reserve_lazy(VirtPageNum(0x1000), 16, flags);
+    assert_eq!(space.translate(VirtAddr(0x100_0000)), None, "reserved page is not mapped");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_3008)), Ok(true), "first touch of reserved page");
+    let (pa, page_flags, level) = space.translate(VirtAddr(0x100_3008)).expect("touched page");
+    assert_eq!((page_flags, level), (Sv39Flags::V | flags, PageLevel(0)), "touched page mapped");
+    assert_eq!(unsafe { *(memory.phys_to_virt(pa).0 as *const u64) }, 0, "touched page is zeroed");
+    assert_eq!(space.translate(VirtAddr(0x100_4000)), None, "other reserved pages are not mapped");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_3000)), Ok(false), "touch mapped page again");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x101_0000)), Ok(false), "touch page out of reserved area");
+    space.unmap(VirtPageNum(0x1002), 4).expect("unmap middle of reserved area");
+    assert_eq!(space.translate(VirtAddr(0x100_3000)), None, "touched page unmapped");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_3000)), Ok(false), "touch unreserved page");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_1000)), Ok(true), "touch reserved page before hole");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_6000)), Ok(true), "touch reserved page after hole");
+}
+
+#[test]
+fn write_execute() {
+    let memory = FakeMemory::new();
+    assert!(Sv39::flags_is_write_execute(&(Sv39Flags::W | Sv39Flags::X)), "writable and executable");
+    assert!(!Sv39::flags_is_write_execute(&(Sv39Flags::R | Sv39Flags::X)), "executable only");
+    assert!(!Sv39::flags_is_write_execute(&(Sv39Flags::R | Sv39Flags::W)), "writable only");
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::X;
+    space.allocate_map_write_execute(VirtPageNum(0x1000), PhysPageNum(0x0), 1, flags).expect("map with explicit opt-in");
+    assert_eq!(space.translate(VirtAddr(0x100_0000)), Some((PhysAddr(0x0), Sv39Flags::V | flags, PageLevel(0))), "writable and executable page");
+}
+
+#[test]
+fn owned_map() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let flags = Sv39Flags::U | Sv39Flags::R | Sv39Flags::W;
+    space.allocate_map_owned(VirtPageNum(0x1000), 3, flags).expect("map owned frames");
+    let pages: Vec<_> = (0..3).map(|i| space.translate(VirtAddr(0x100_0000 + i * 0x1000)).expect("owned page mapped")).collect();
+    for &(pa, page_flags, level) in &pages {
+        assert_eq!((page_flags, level), (Sv39Flags::V | flags, PageLevel(0)), "owned page flags");
+        assert_eq!(memory.frame_ref_count(pa.page_number::<Sv39>()), 1, "owned frame allocated");
+        assert_eq!(unsafe { *(memory.phys_to_virt(pa).0 as *const u64) }, 0, "owned frame zeroed");
+    }
+    assert_ne!(pages[0].0, pages[1].0, "each page has its own frame");
+    space.unmap(VirtPageNum(0x1001), 1).expect("unmap owned page");
+    assert_eq!(space.translate(VirtAddr(0x100_1000)), None, "owned page unmapped");
+    assert_eq!(space.leaf_frames.len(), 2, "frame of unmapped page freed");
+    let wx = Sv39Flags::U | Sv39Flags::R | Sv39Flags::W | Sv39Flags::X;
+    space.allocate_map_owned_write_execute(VirtPageNum(0x2000), 1, wx).expect("map owned writable and executable frame");
+    assert_eq!(space.translate(VirtAddr(0x200_0000)).map(|(_, flags, _)| flags), Some(Sv39Flags::V | wx), "writable and executable owned page");
+    drop(space);
+    assert_eq!(memory.free_frames(), total_frames, "owned frames freed with address space");
+}
+
+#[test]
+fn protect() {
+    let memory = FakeMemory::new();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let (ro, rw) = (Sv39Flags::U | Sv39Flags::R, Sv39Flags::U | Sv39Flags::R | Sv39Flags::W);
+    // 只修改2M大页中的一页，需要拆分大页
+    space.allocate_map(VirtPageNum(0x200), PhysPageNum(0x200), 0x200, rw).expect("map 2M page");
+    space.protect(VirtPageNum(0x201), 1, ro).expect("protect one page in huge page");
+    assert_eq!(space.translate(VirtAddr(0x20_1000)), Some((PhysAddr(0x20_1000), Sv39Flags::V | ro, PageLevel(0))), "protected page");
+    assert_eq!(space.translate(VirtAddr(0x20_2000)), Some((PhysAddr(0x20_2000), Sv39Flags::V | rw, PageLevel(0))), "page after protected page");
+    space.protect(VirtPageNum(0x200), 0x200, Sv39Flags::U | Sv39Flags::R | Sv39Flags::X).expect("protect whole range");
+    let all_executable = space.walk(VirtPageNum(0x200), 0x200).all(|(_, _, flags, _)| flags.contains(Sv39Flags::X));
+    assert!(all_executable, "all split pages protected");
+    // 预留的页在分配时使用新的权限
+    space.reserve_lazy(VirtPageNum(0x1000), 4, rw);
+    space.protect(VirtPageNum(0x1001), 2, ro).expect("protect reserved pages");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_1000)), Ok(true), "touch protected reserved page");
+    assert_eq!(space.translate(VirtAddr(0x100_1000)).map(|(_, flags, _)| flags), Some(Sv39Flags::V | ro), "reserved page allocated read only");
+    assert_eq!(space.resolve_lazy_fault(VirtAddr(0x100_3000)), Ok(true), "touch reserved page after protected pages");
+    assert_eq!(space.translate(VirtAddr(0x100_3000)).map(|(_, flags, _)| flags), Some(Sv39Flags::V | rw), "reserved page keeps old flags");
+    // 共享的页帧要求可写时，只标记为写时复制
+    space.protect(VirtPageNum(0x1001), 1, ro).expect("protect touched page");
+    let child = space.fork_cow().expect("fork address space");
+    space.protect(VirtPageNum(0x1001), 1, rw).expect("protect shared page as writable");
+    let (_, flags, _) = space.translate(VirtAddr(0x100_1000)).expect("shared page");
+    assert_eq!(flags, Sv39Flags::V | ro | Sv39Flags::COW, "shared page is copy-on-write");
+    drop(child);
+}
+
+#[test]
+fn user_access() {
+    let memory = FakeMemory::new();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let (ro, rw) = (Sv39Flags::U | Sv39Flags::R, Sv39Flags::U | Sv39Flags::R | Sv39Flags::W);
+    space.allocate_map_owned(VirtPageNum(0x1000), 2, rw).expect("map user pages");
+    space.allocate_map_owned(VirtPageNum(0x1002), 1, ro).expect("map read only page");
+    space.allocate_map_owned(VirtPageNum(0x1003), 1, Sv39Flags::R | Sv39Flags::W).expect("map kernel page");
+    space.reserve_lazy(VirtPageNum(0x1004), 1, rw);
+    // 跨过页边界的缓冲区
+    let data: Vec<u8> = (0..8).collect();
+    assert_eq!(space.copy_to_user(VirtAddr(0x100_0ffc), &data), Ok(()), "copy across pages");
+    let mut buf = [0u8; 8];
+    assert_eq!(space.copy_from_user(VirtAddr(0x100_0ffc), &mut buf), Ok(()), "copy back across pages");
+    assert_eq!(&buf[..], &data[..], "data copied across pages");
+    assert_eq!(space.copy_from_user(VirtAddr(0x100_2ffc), &mut buf), Err(UserAccessError::Fault(VirtAddr(0x100_3000))), "read kernel page");
+    assert_eq!(space.copy_to_user(VirtAddr(0x100_2000), &data), Err(UserAccessError::Fault(VirtAddr(0x100_2000))), "write read only page");
+    assert_eq!(space.copy_from_user(VirtAddr(0x100_5000), &mut buf), Err(UserAccessError::Fault(VirtAddr(0x100_5000))), "read unmapped page");
+    assert_eq!(space.copy_from_user(VirtAddr(usize::MAX - 3), &mut buf), Err(UserAccessError::Fault(VirtAddr(usize::MAX - 3))), "address overflow");
+    assert_eq!(space.copy_from_user(VirtAddr(0x100_4000), &mut buf), Ok(()), "read reserved page");
+    assert_eq!(buf, [0; 8], "reserved page is zeroed");
+    // 写时复制的页在写入前复制
+    let mut child = space.fork_cow().expect("fork address space");
+    assert_eq!(child.copy_to_user(VirtAddr(0x100_0000), &[0x66]), Ok(()), "write copy-on-write page");
+    assert_eq!(child.copy_from_user(VirtAddr(0x100_0000), &mut buf[..1]), Ok(()), "read written page");
+    assert_eq!(buf[0], 0x66, "child sees written data");
+    assert_eq!(space.copy_from_user(VirtAddr(0x100_0000), &mut buf[..1]), Ok(()), "read parent page");
+    assert_eq!(buf[0], 0, "parent page unchanged");
+}
+
+#[test]
+fn shared_memory() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    let shared = SharedMemory::try_new_in::<Sv39>(2, &memory).expect("create shared memory");
+    let (ro, rw) = (Sv39Flags::U | Sv39Flags::R, Sv39Flags::U | Sv39Flags::R | Sv39Flags::W);
+    let mut writer = MemorySet::try_new_in(Sv39, &memory).expect("create writer memory set");
+    let mut reader = MemorySet::try_new_in(Sv39, &memory).expect("create reader memory set");
+    writer.map_shared(AreaKind::Mmap, VirtPageNum(0x1000), &shared, rw).expect("map shared memory as writable");
+    reader.map_shared(AreaKind::Mmap, VirtPageNum(0x2000), &shared, ro).expect("map shared memory as read only");
+    // 一个地址空间写入的数据，另一个地址空间可以读出
+    writer.addr_space_mut().copy_to_user(VirtAddr(0x100_0ffe), &[1, 2, 3, 4]).expect("write shared memory");
+    let mut buf = [0u8; 4];
+    reader.addr_space_mut().copy_from_user(VirtAddr(0x200_0ffe), &mut buf).expect("read shared memory");
+    assert_eq!(buf, [1, 2, 3, 4], "data shared between address spaces");
+    assert_eq!(reader.addr_space_mut().copy_to_user(VirtAddr(0x200_0000), &[0]), Err(UserAccessError::Fault(VirtAddr(0x200_0000))), "read only mapping");
+    // 复制地址空间时，共享内存仍然共享，不使用写时复制
+    let mut child = writer.addr_space_mut().fork_cow().expect("fork address space");
+    let (_, flags, _) = child.translate(VirtAddr(0x100_0000)).expect("shared page in child");
+    assert_eq!(flags, Sv39Flags::V | rw | Sv39Flags::SHARED, "shared page stays writable");
+    child.copy_to_user(VirtAddr(0x100_0000), &[5]).expect("child writes shared memory");
+    reader.addr_space_mut().copy_from_user(VirtAddr(0x200_0000), &mut buf[..1]).expect("read child write");
+    assert_eq!(buf[0], 5, "child writes to the same frame");
+    // 对象和每个映射各持有一份引用
+    let (pa, _, _) = reader.addr_space().translate(VirtAddr(0x200_0000)).expect("shared page");
+    assert_eq!(memory.frame_ref_count(pa.page_number::<Sv39>()), 4, "object and three mappings");
+    drop(shared);
+    drop(child);
+    writer.remove_area(VirtAddr(0x100_0000)).expect("unmap shared memory");
+    assert_eq!(memory.frame_ref_count(pa.page_number::<Sv39>()), 1, "last mapping keeps frame alive");
+    drop(writer);
+    drop(reader);
+    assert_eq!(memory.free_frames(), total_frames, "shared frames freed after last mapping");
+}
+
+#[test]
+fn global_map() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    let mut kernel = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create kernel address space");
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::G;
+    kernel.allocate_map(VirtPageNum(0x40_000), PhysPageNum(0x80000), 0x10, flags).expect("map kernel pages");
+    kernel.set_global(VirtPageNum(0x40_000), 0x10);
+    let mut user = unsafe { PagedAddrSpace::try_new_with_global(&kernel) }.expect("create user address space");
+    assert!(user.frames.is_empty(), "kernel page tables are shared, not copied");
+    let ans = user.translate(VirtAddr(0x4000_1234));
+    assert_eq!(ans, Some((PhysAddr(0x8000_1234), Sv39Flags::V | flags, PageLevel(0))), "kernel mapping in user space");
+    // 内核之后建立的映射也出现在所有共享的地址空间中
+    kernel.allocate_map(VirtPageNum(0x40_100), PhysPageNum(0x80100), 1, flags).expect("map more kernel pages");
+    assert!(user.translate(VirtAddr(0x4010_0000)).is_some(), "later kernel mapping visible in user space");
+    // 用户自己的映射不影响内核
+    user.allocate_map_owned(VirtPageNum(0x1000), 1, Sv39Flags::U | Sv39Flags::R | Sv39Flags::W).expect("map user page");
+    assert_eq!(kernel.translate(VirtAddr(0x100_0000)), None, "user mapping not in kernel space");
+    let child = user.fork_cow().expect("fork user address space");
+    assert!(child.translate(VirtAddr(0x4000_0000)).is_some() && child.translate(VirtAddr(0x100_0000)).is_some(), "child keeps both mappings");
+    assert_eq!(child.frames.len(), 2, "child copies only user page tables");
+    drop(child);
+    drop(user);
+    drop(kernel);
+    assert_eq!(memory.free_frames(), total_frames, "all page tables freed");
+}
+
+#[test]
+fn kernel_and_app_memory_sets() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    // 和boot_paged一样，内核的区域直接映射到物理内存，启动栈的下方留出保护页，然后设为全局映射
+    let mut kernel = MemorySet::try_new_in(Sv39, &memory).expect("create kernel memory set");
+    let (ro, rw) = (Sv39Flags::R | Sv39Flags::G, Sv39Flags::R | Sv39Flags::W | Sv39Flags::G);
+    kernel.map_direct(AreaKind::Rodata, VirtPageNum(0x40_000), PhysPageNum(0x80000), 0x10, ro).expect("map kernel rodata");
+    kernel.map_stack_direct(VirtPageNum(0x40_011), PhysPageNum(0x80011), 0x10, rw).expect("map kernel stack");
+    kernel.map_direct(AreaKind::Linear, VirtPageNum(0x40_021), PhysPageNum(0x80021), 0x100, rw).expect("map linear area");
+    assert!(!kernel.is_free(VirtPageNum(0x40_010), 1), "guard page below kernel stack");
+    kernel.addr_space_mut().set_global(VirtPageNum(0x40_000), 0x121);
+    // 和new_app_memory_set一样，应用程序的内存集合共享内核的全局映射，bss段立即分配清零的页帧
+    let mut app = unsafe { MemorySet::try_new_with_global(&kernel) }.expect("create app memory set");
+    assert_eq!(app.areas().count(), 0, "kernel areas not recorded in app memory set");
+    assert!(app.addr_space().translate(VirtAddr(0x4000_0000)).is_some(), "kernel mapping in app memory set");
+    let bss = Sv39Flags::U | Sv39Flags::R | Sv39Flags::W;
+    assert!(app.is_free(VirtPageNum(0x1000), 2), "bss range is free");
+    app.map_owned(AreaKind::Bss, VirtPageNum(0x1000), 2, bss).expect("map bss area");
+    assert!(!app.is_free(VirtPageNum(0x1001), 4), "range overlaps bss area");
+    let (pa, flags, _) = app.addr_space().translate(VirtAddr(0x100_1008)).expect("bss page mapped");
+    assert_eq!(flags, Sv39Flags::V | bss, "bss page flags");
+    assert_eq!(unsafe { *(memory.phys_to_virt(pa).0 as *const u64) }, 0, "bss page zeroed");
+    drop(app);
+    drop(kernel);
+    assert_eq!(memory.free_frames(), total_frames, "all frames freed");
+}
+
+#[test]
+fn memory_set() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    let mut set = MemorySet::try_new_in(Sv39, &memory).expect("create memory set");
+    let (text, data) = (Sv39Flags::U | Sv39Flags::R | Sv39Flags::X, Sv39Flags::U | Sv39Flags::R | Sv39Flags::W);
+    set.map_direct(AreaKind::Text, VirtPageNum(0x1000), PhysPageNum(0x80400), 4, text).expect("map text area");
+    set.map_stack(VirtPageNum(0x2000), 0x10, data);
+    set.map_copied(AreaKind::Data, VirtPageNum(0x3000), 2, data, &[1, 2, 3, 4], 0xffe).expect("map data area");
+    let kinds: Vec<_> = set.areas().map(|area| area.kind).collect();
+    assert_eq!(kinds, [AreaKind::Text, AreaKind::Guard, AreaKind::Stack, AreaKind::Data], "areas sorted by address");
+    assert!(set.is_guard(VirtAddr(0x1ff_f008)), "guard page below stack");
+    assert_eq!(set.addr_space_mut().resolve_lazy_fault(VirtAddr(0x1ff_f008)), Ok(false), "guard page is never mapped");
+    assert_eq!(set.find_area(VirtAddr(0x200_5678)).map(|area| area.kind), Some(AreaKind::Stack), "find stack area");
+    assert!(set.find_area(VirtAddr(0x201_0000)).is_none(), "find address out of areas");
+    // 复制的数据跨过了页的边界
+    let read = |set: &MemorySet<Sv39, &FakeMemory>, va: usize| {
+        let (pa, _, _) = set.addr_space().translate(VirtAddr(va)).expect("copied page mapped");
+        unsafe { *(memory.phys_to_virt(pa).0 as *const u8) }
+    };
+    let bytes: Vec<_> = [0x300_0ffd, 0x300_0ffe, 0x300_0fff, 0x300_1000, 0x300_1001, 0x300_1002].iter().map(|&va| read(&set, va)).collect();
+    assert_eq!(bytes, [0, 1, 2, 3, 4, 0], "data copied into area");
+    assert_eq!(set.addr_space_mut().resolve_lazy_fault(VirtAddr(0x200_f000)), Ok(true), "touch anonymous area");
+    // 栈向低地址扩大，其它区域向高地址扩大
+    set.grow_area(VirtAddr(0x200_0000), 4).expect("grow stack area");
+    assert_eq!(set.find_area(VirtAddr(0x1ffc_000)).map(|area| area.range.clone()), Some(VirtPageNum(0x1ffc)..VirtPageNum(0x2010)), "stack grows down");
+    assert_eq!(set.addr_space_mut().resolve_lazy_fault(VirtAddr(0x1ffc_000)), Ok(true), "touch grown stack");
+    assert!(set.is_guard(VirtAddr(0x1ff_b000)) && !set.is_guard(VirtAddr(0x1ff_f000)), "guard page moves with stack");
+    set.grow_area(VirtAddr(0x100_0000), 2).expect("grow text area");
+    let ans = set.addr_space().translate(VirtAddr(0x100_5000));
+    assert_eq!(ans, Some((PhysAddr(0x8040_5000), Sv39Flags::V | text, PageLevel(0))), "grown direct area maps next frames");
+    let removed = set.remove_area(VirtAddr(0x300_1000)).expect("remove data area");
+    assert_eq!(removed.map(|area| area.kind), Some(AreaKind::Data), "removed data area");
+    assert_eq!(set.addr_space().translate(VirtAddr(0x300_1000)), None, "removed area unmapped");
+    assert!(set.find_area(VirtAddr(0x300_1000)).is_none(), "removed area forgotten");
+    assert!(set.remove_area(VirtAddr(0x300_1000)).expect("remove nothing").is_none(), "remove area twice");
+    // 修改区域中间一段的权限，区域被拆分成三段，直接映射的部分保持原来的物理页
+    assert_eq!(set.protect(VirtPageNum(0x1001), 2, Sv39Flags::U | Sv39Flags::R), Ok(true), "protect middle of text area");
+    let ranges: Vec<_> = set.areas().filter(|area| area.kind == AreaKind::Text).map(|area| (area.range.clone(), area.flags)).collect();
+    assert_eq!(ranges, [
+        (VirtPageNum(0x1000)..VirtPageNum(0x1001), text),
+        (VirtPageNum(0x1001)..VirtPageNum(0x1003), Sv39Flags::U | Sv39Flags::R),
+        (VirtPageNum(0x1003)..VirtPageNum(0x1006), text),
+    ], "text area split");
+    assert_eq!(set.find_area(VirtAddr(0x100_3000)).map(|area| area.backing), Some(AreaBacking::Direct(PhysPageNum(0x80403))), "split direct area keeps offset");
+    assert_eq!(set.protect(VirtPageNum(0x1005), 2, text), Ok(false), "protect pages out of areas");
+    set.remove_area(VirtAddr(0x200_0000)).expect("remove stack area");
+    assert!(!set.is_guard(VirtAddr(0x1ff_b000)), "guard page removed with stack");
+    drop(set);
+    assert_eq!(memory.free_frames(), total_frames, "frames freed with memory set");
+}
+
+#[test]
+fn sv32_unmap_translate() {
+    let memory = FakeMemory::new();
+    let total_frames = memory.free_frames();
+    let mut space = PagedAddrSpace::try_new_in(Sv32, &memory).expect("create address space");
+    let flags = Sv32Flags::R | Sv32Flags::W;
+    // 一个4M大页和三个4K页
+    space.allocate_map(VirtPageNum(0x400), PhysPageNum(0x80400), 0x400 + 3, flags).expect("map huge pages");
+    let ans = space.translate(VirtAddr(0x40_1234));
+    assert_eq!(ans, Some((PhysAddr(0x8040_1234), flags | Sv32Flags::V, PageLevel(1))), "translate in 4M page");
+    let ans = space.translate(VirtAddr(0x80_2234));
+    assert_eq!(ans, Some((PhysAddr(0x8080_2234), flags | Sv32Flags::V, PageLevel(0))), "translate in 4K page");
+    assert_eq!(space.translate(VirtAddr(0x80_3000)), None, "translate unmapped address");
+    // 取消4M大页中一页的映射，大页被拆分成4K页
+    space.unmap(VirtPageNum(0x401), 1).expect("unmap one page in huge page");
+    assert_eq!(space.translate(VirtAddr(0x40_1234)), None, "translate unmapped page in split huge page");
+    let ans = space.translate(VirtAddr(0x40_2234));
+    assert_eq!(ans, Some((PhysAddr(0x8040_2234), flags | Sv32Flags::V, PageLevel(0))), "translate in split 4M page");
+    let levels: Vec<_> = space.walk(VirtPageNum(0x0), 0x1000).map(|(_, _, _, level)| level.0).collect();
+    assert_eq!(levels, vec![0; 0x400 - 1 + 3], "walk through all leaf entries");
+    space.unmap(VirtPageNum(0x400), 0x400 + 3).expect("unmap all");
+    assert!(space.frames.is_empty(), "all intermediate page tables freed");
+    let root = unsafe { unref_ppn_mut::<Sv32, _>(&memory, space.root_page_number()) };
+    assert!(Sv32::page_table_is_empty(root), "root page table is empty");
+    drop(space);
+    assert_eq!(memory.free_frames(), total_frames, "page tables freed with address space");
+}
+
+#[test]
+fn high_half_huge_pages() {
+    let memory = FakeMemory::new();
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &memory).expect("create address space");
+    let flags = Sv39Flags::R | Sv39Flags::W | Sv39Flags::G;
+    // 和内核的线性映射一样，用两个1G大页把0x8000_0000开始的物理内存映射到高地址
+    let vpn = VirtAddr(0xffff_ffff_8000_0000).page_number::<Sv39>();
+    space.allocate_map_write_execute(vpn, PhysPageNum(0x80000), 0x80000, flags).expect("map high half huge pages");
+    let ans = space.translate(VirtAddr(0xffff_ffff_c000_1234));
+    assert_eq!(ans, Some((PhysAddr(0xc000_1234), Sv39Flags::V | flags, PageLevel(2))), "translate in second 1G page");
+    // 修改和取消大页中一部分的映射，需要按大页的起始页号拆分
+    space.protect(VirtPageNum(vpn.0 + 0x40_001), 1, Sv39Flags::R).expect("protect one page in huge page");
+    let ans = space.translate(VirtAddr(0xffff_ffff_c000_1234));
+    assert_eq!(ans, Some((PhysAddr(0xc000_1234), Sv39Flags::V | Sv39Flags::R | Sv39Flags::G, PageLevel(0))), "protected page in split huge page");
+    space.unmap(VirtPageNum(vpn.0 + 0x40_002), 1).expect("unmap one page in huge page");
+    assert_eq!(space.translate(VirtAddr(0xffff_ffff_c000_2000)), None, "unmapped page in split huge page");
+    let ans = space.translate(VirtAddr(0xffff_ffff_c020_0000));
+    assert_eq!(ans, Some((PhysAddr(0xc020_0000), Sv39Flags::V | flags, PageLevel(1))), "translate in split 2M page");
+    let ans = space.translate(VirtAddr(0xffff_ffff_8000_1234));
+    assert_eq!(ans, Some((PhysAddr(0x8000_1234), Sv39Flags::V | flags, PageLevel(2))), "first 1G page unchanged");
+}
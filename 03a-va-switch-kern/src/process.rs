@@ -0,0 +1,311 @@
+// 应用程序的运行。每个应用程序有自己的地址空间和运行时，包装成Future交给内核的异步执行器调度
+use crate::{app, elf, mm, sbi, task, FRAME_ALLOC};
+use crate::executor::{self, KernelTrap};
+use crate::syscall::{syscall, SyscallOperation};
+use core::future::Future;
+use core::ops::{Generator, GeneratorState};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+pub const USER_STACK_TOP: usize = 0x80000000;
+const USER_STACK_SIZE: usize = 0x10000;
+
+// 每个应用程序一次最多运行的时间，单位是秒的几分之一
+const TIME_SLICES_PER_SECOND: usize = 100;
+
+// 加载应用程序失败的原因
+enum LoadError {
+    // 应用程序不是内核能加载的ELF文件
+    Elf(elf::ElfError),
+    // 段同时可写和可执行，内核拒绝加载
+    WriteExecute(usize),
+    // 段不在用户栈下方的地址区间中，或者和其它的段占用了同一页
+    BadAddress(usize),
+    // 没有足够的页帧复制段的数据
+    OutOfMemory,
+}
+
+// 为应用程序创建自己的地址空间，共享内核的全局映射。内核的映射没有U位，用户不能访问。
+// 按ELF文件的段加载应用程序：代码段可读可执行，只读数据段只读，数据段和bss段可读可写；返回地址空间和入口地址
+fn new_app_memory_set<'a, M: mm::PageMode<Flags = mm::Sv39Flags>>(
+    kernel_memory_set: &mm::MemorySet<M, &'a mm::DefaultFrameAllocator>,
+    app_id: usize
+) -> Result<(mm::MemorySet<M, &'a mm::DefaultFrameAllocator>, usize), LoadError> {
+    let file = elf::parse(app::APP_MANAGER.app_data(app_id)).map_err(LoadError::Elf)?;
+    // 内核的内存集合在boot_paged中创建，永远不会被释放
+    let mut memory_set = unsafe { mm::MemorySet::try_new_with_global(kernel_memory_set) }
+        .expect("allocate page to create app paged address space");
+    for segment in file.segments.iter().filter(|segment| segment.mem_size != 0) {
+        if segment.writable && segment.executable {
+            return Err(LoadError::WriteExecute(segment.vaddr))
+        }
+        // 用户程序的链接脚本把每一节按页对齐，不同的段不会占用同一页
+        let page_size = 1 << M::FRAME_SIZE_BITS;
+        let segment_end = segment.vaddr + segment.mem_size;
+        if segment_end > USER_STACK_TOP {
+            return Err(LoadError::BadAddress(segment.vaddr))
+        }
+        let offset = mm::VirtAddr(segment.vaddr).page_offset::<M>();
+        let vpn = mm::VirtAddr(segment.vaddr).page_number::<M>();
+        let n = (offset + segment.mem_size + page_size - 1) / page_size;
+        if !memory_set.is_free(vpn, n) {
+            return Err(LoadError::BadAddress(segment.vaddr))
+        }
+        let mut flags = mm::Sv39Flags::U;
+        flags.set(mm::Sv39Flags::R, segment.readable);
+        flags.set(mm::Sv39Flags::W, segment.writable);
+        flags.set(mm::Sv39Flags::X, segment.executable);
+        let kind = match (segment.executable, segment.writable) {
+            (true, _) => mm::AreaKind::Text,
+            (false, true) if segment.data.is_empty() => mm::AreaKind::Bss,
+            (false, true) => mm::AreaKind::Data,
+            (false, false) => mm::AreaKind::Rodata,
+        };
+        let result = if kind == mm::AreaKind::Bss {
+            // bss段在文件中没有数据，直接分配清零的页帧
+            memory_set.map_owned(kind, vpn, n, flags)
+        } else {
+            memory_set.map_copied(kind, vpn, n, flags, segment.data, offset)
+        };
+        result.map_err(|_| LoadError::OutOfMemory)?;
+    }
+    // 用户栈只预留地址区间，用到哪一页才分配哪一页；栈的下方是保护页
+    let guard_vpn = mm::VirtAddr(USER_STACK_TOP - USER_STACK_SIZE - 0x1000).page_number::<M>();
+    if !memory_set.is_free(guard_vpn, USER_STACK_SIZE / 0x1000 + 1) {
+        return Err(LoadError::BadAddress(USER_STACK_TOP - USER_STACK_SIZE))
+    }
+    memory_set.map_stack(
+        mm::VirtAddr(USER_STACK_TOP - USER_STACK_SIZE).page_number::<M>(), 
+        USER_STACK_SIZE / 0x1000,
+        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::U
+    );
+    Ok((memory_set, file.entry))
+}
+
+// 一个加载好的应用程序，包括它的运行时和地址空间
+struct Process<M: mm::PageMode> {
+    app_id: usize,
+    rt: executor::Runtime,
+    memory_set: mm::MemorySet<M, &'static mm::DefaultFrameAllocator>,
+    time_slice: usize,
+}
+
+// 正在运行的应用程序数，内核的异步任务用它判断应用程序是否都已结束
+static RUNNING_APPS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+impl<M: mm::PageMode> Drop for Process<M> {
+    fn drop(&mut self) {
+        RUNNING_APPS.fetch_sub(1, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+// 用户的运行时包装成Future：处理系统调用和异常，直到时间片用完或者让出处理核时返回Pending，
+// 应用程序结束或者出错时返回Ready，这时释放地址空间，回收页帧和地址空间编号
+impl<M: mm::PageMode<Flags = mm::Sv39Flags> + Unpin> Future for Process<M> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        use riscv::register::time;
+        sbi::set_timer(time::read().wrapping_add(self.time_slice));
+        loop {
+            // 每次回到用户之前重新得到satp的值，地址空间编号回绕到新的一代时，应用程序会得到新的编号
+            let satp = unsafe { self.memory_set.addr_space_mut().prepare_activate(mm::satp_with_current_mode) };
+            self.rt.set_user_satp(satp);
+            let process = &mut *self;
+            let (memory_set, rt) = (&mut process.memory_set, &mut process.rt);
+            match Pin::new(&mut *rt).resume(()) {
+                GeneratorState::Yielded(KernelTrap::Timer()) => {
+                    // 时间片用完，唤醒自己，排到就绪队列的最后
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending
+                },
+                GeneratorState::Yielded(KernelTrap::Syscall()) => {
+                    let ctx = rt.context_mut();
+                    match syscall(memory_set, ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5]) {
+                        SyscallOperation::Return(ans) => {
+                            ctx.a0 = ans.code;
+                            ctx.a1 = ans.extra;
+                            ctx.sepc = ctx.sepc.wrapping_add(4);
+                        }
+                        SyscallOperation::Yield => {
+                            ctx.a0 = 0;
+                            ctx.a1 = 0;
+                            ctx.sepc = ctx.sepc.wrapping_add(4);
+                            cx.waker().wake_by_ref();
+                            return Poll::Pending
+                        }
+                        SyscallOperation::Terminate(code) => {
+                            println!("[Kernel] Process app_{} returned with code {}", process.app_id, code);
+                            return Poll::Ready(())
+                        }
+                        SyscallOperation::UserPanic(file, line, col, msg) => {
+                            let file = file.as_deref().unwrap_or("<no file>");
+                            let msg = msg.as_deref().unwrap_or("<no message>");
+                            println!("[Kernel] User process panicked at '{}', {}:{}:{}", msg, file, line, col);
+                            return Poll::Ready(())
+                        }
+                    }
+                },
+                GeneratorState::Yielded(KernelTrap::LoadAccessFault(a, sepc)) => {
+                    println!("[kernel] Load access fault to {:#x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::StoreAccessFault(a, sepc)) => {
+                    println!("[kernel] Store access fault to {:#x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::IllegalInstruction(a, sepc)) => {
+                    println!("[kernel] Illegal instruction {:x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::InstructionMisaligned(a, sepc)) => {
+                    println!("[kernel] Misaligned instruction fetch from {:#x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::InstructionAccessFault(a, sepc)) => {
+                    println!("[kernel] Instruction access fault to {:#x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::LoadMisaligned(a, sepc)) => {
+                    println!("[kernel] Misaligned load from {:#x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::StoreMisaligned(a, sepc)) => {
+                    println!("[kernel] Misaligned store to {:#x} in {:#x}, core dumped.", a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::Breakpoint(sepc)) => {
+                    // 断点不是错误，跳过ebreak指令继续运行。压缩指令c.ebreak长2个字节，它的低两位不是0b11
+                    let mut inst = [0u8; 2];
+                    if memory_set.addr_space_mut().copy_from_user(mm::VirtAddr(sepc), &mut inst).is_err() {
+                        println!("[kernel] Breakpoint at unreadable {:#x}, core dumped.", sepc);
+                        return Poll::Ready(())
+                    }
+                    println!("[kernel] Breakpoint in app {} at {:#x}, continue.", process.app_id, sepc);
+                    let len = if inst[0] & 0b11 == 0b11 { 4 } else { 2 };
+                    rt.context_mut().sepc = sepc.wrapping_add(len);
+                },
+                GeneratorState::Yielded(KernelTrap::SoftwareInterrupt()) => {
+                    // 内核没有使用软件中断，清除后继续运行
+                    unsafe { riscv::register::sip::clear_ssoft() };
+                },
+                GeneratorState::Yielded(KernelTrap::ExternalInterrupt()) => {
+                    // 内核还没有外部设备的驱动，关闭外部中断后继续运行
+                    unsafe { riscv::register::sie::clear_sext() };
+                },
+                GeneratorState::Yielded(KernelTrap::Unknown(scause, a, sepc)) => {
+                    println!("[kernel] Unknown trap {:#x}, stval {:#x} in {:#x}, core dumped.", scause, a, sepc);
+                    return Poll::Ready(())
+                },
+                GeneratorState::Yielded(KernelTrap::StorePageFault(a, sepc)) => {
+                    // 写入写时复制的页或者预留的页，分配页帧后回到用户，重新执行写入的指令
+                    let resolved = memory_set.addr_space_mut().resolve_cow_fault(mm::VirtAddr(a))
+                        .and_then(|ok| if ok { Ok(true) } else { memory_set.addr_space_mut().resolve_lazy_fault(mm::VirtAddr(a)) });
+                    match resolved {
+                        Ok(true) => {},
+                        Ok(false) if memory_set.is_guard(mm::VirtAddr(a)) => {
+                            println!("[kernel] Stack overflow in app {}, store to {:#x} in {:#x}, core dumped.", 
+                                process.app_id, a, sepc);
+                            return Poll::Ready(())
+                        },
+                        Ok(false) => {
+                            println!("[kernel] Store page fault to {:#x} in {:#x}, core dumped.", a, sepc);
+                            return Poll::Ready(())
+                        },
+                        Err(_) => {
+                            println!("[kernel] Out of memory when copying page {:#x} in {:#x}, core dumped.", a, sepc);
+                            return Poll::Ready(())
+                        }
+                    }
+                },
+                GeneratorState::Yielded(KernelTrap::LoadPageFault(a, sepc)) => {
+                    match memory_set.addr_space_mut().resolve_lazy_fault(mm::VirtAddr(a)) {
+                        Ok(true) => {},
+                        Ok(false) if memory_set.is_guard(mm::VirtAddr(a)) => {
+                            println!("[kernel] Stack overflow in app {}, load from {:#x} in {:#x}, core dumped.", 
+                                process.app_id, a, sepc);
+                            return Poll::Ready(())
+                        },
+                        Ok(false) => {
+                            println!("[kernel] Load page fault to {:#x} in {:#x}, core dumped.", a, sepc);
+                            return Poll::Ready(())
+                        },
+                        Err(_) => {
+                            println!("[kernel] Out of memory when loading page {:#x} in {:#x}, core dumped.", a, sepc);
+                            return Poll::Ready(())
+                        }
+                    }
+                },
+                GeneratorState::Yielded(KernelTrap::InstructionPageFault(a, sepc)) => {
+                    match memory_set.addr_space_mut().resolve_lazy_fault(mm::VirtAddr(a)) {
+                        Ok(true) => {},
+                        Ok(false) => {
+                            println!("[kernel] Instruction page fault to {:#x} in {:#x}, core dumped.", a, sepc);
+                            return Poll::Ready(())
+                        },
+                        Err(_) => {
+                            println!("[kernel] Out of memory when loading page {:#x} in {:#x}, core dumped.", a, sepc);
+                            return Poll::Ready(())
+                        }
+                    }
+                },
+                GeneratorState::Complete(()) => return Poll::Ready(()),
+            }
+        }
+    }
+}
+
+// 内核自己的异步任务：等到所有的应用程序结束，报告页帧的使用情况，检查应用程序的页帧是否都已回收
+async fn report_frames_after_apps(frame_alloc: &'static mm::DefaultFrameAllocator) {
+    while RUNNING_APPS.load(core::sync::atomic::Ordering::Relaxed) != 0 {
+        task::yield_now().await;
+    }
+    let stats = frame_alloc.lock().stats();
+    println!("[kernel-frame] Free frames after all apps: {}/{}", stats.free_frames, stats.total_frames);
+}
+
+// 一次加载所有的应用程序，每个应用程序的运行时作为一个任务，和内核的异步任务一起由执行器调度。
+// 每个应用程序运行一个时间片后，时钟中断回到内核，换下一个就绪的任务运行
+pub fn execute<M: mm::PageMode<Flags = mm::Sv39Flags> + Unpin + 'static>(
+    kernel_memory_set: &mm::MemorySet<M, &'static mm::DefaultFrameAllocator>, 
+    user_stack: usize,
+    timebase_frequency: usize
+) -> ! {
+    app::APP_MANAGER.print_app_info();
+    let task_executor = task::Executor::new();
+    let time_slice = timebase_frequency / TIME_SLICES_PER_SECOND;
+    for app_id in 0..app::APP_MANAGER.num_app() {
+        println!("[kernel] Loading app_{}", app_id);
+        let (memory_set, entry) = match new_app_memory_set(kernel_memory_set, app_id) {
+            Ok(ans) => ans,
+            Err(LoadError::Elf(err)) => {
+                println!("[kernel] Failed to load app_{}: bad elf file, {:?}", app_id, err);
+                continue
+            },
+            Err(LoadError::WriteExecute(va)) => {
+                println!("[kernel] Failed to load app_{}: writable and executable segment at {:#x}", app_id, va);
+                continue
+            },
+            Err(LoadError::BadAddress(va)) => {
+                println!("[kernel] Failed to load app_{}: segment at {:#x} out of user space or overlapped", app_id, va);
+                continue
+            },
+            Err(LoadError::OutOfMemory) => {
+                println!("[kernel] Failed to load app_{}: out of memory", app_id);
+                continue
+            },
+        };
+        let rt = executor::Runtime::new_user(entry, user_stack);
+        RUNNING_APPS.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        task_executor.spawn(Process { app_id, rt, memory_set, time_slice });
+    }
+    // 程序通过线性映射写入，执行之前需要同步指令缓存
+    unsafe { asm!("fence.i") };
+    task_executor.spawn(report_frames_after_apps(FRAME_ALLOC.get().expect("frame allocator initialized")));
+    // 内核还没有能唤醒任务的中断源，等待唤醒的任务永远不会再运行
+    let waiting = task_executor.run_until_idle();
+    if waiting != 0 {
+        println!("[kernel] {} tasks still waiting", waiting);
+    }
+    println!("All applications completed, shutdown!");
+    sbi::shutdown()
+}
@@ -1,14 +1,52 @@
+use crate::mm::{self, AreaBacking, AreaKind, MemorySet, PageMode, Sv39Flags, UserAccessError, VirtAddr, VirtPageNum};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+const FUNCTION_PROCESS_YIELD: usize = 0x1919811;
 
 const MODULE_TEST_INTERFACE: usize = 0x233666;
 const FUNCTION_TEST_WRITE: usize = 0x666233;
 
+const MODULE_MEMORY: usize = 0x10086;
+const FUNCTION_MEMORY_PROTECT: usize = 0x10010;
+const FUNCTION_MEMORY_SHARE_CREATE: usize = 0x10011;
+const FUNCTION_MEMORY_SHARE_MAP: usize = 0x10012;
+const FUNCTION_MEMORY_SHARE_UNMAP: usize = 0x10013;
+const FUNCTION_MEMORY_SHARE_CLOSE: usize = 0x10014;
+
+// 内存保护的权限位，和用户库中的定义一致
+const PROT_READ: usize = 1 << 0;
+const PROT_WRITE: usize = 1 << 1;
+const PROT_EXEC: usize = 1 << 2;
+
+// 系统调用失败时，code为负数的错误码
+const ERROR_INVALID_ARGUMENT: isize = -22;
+const ERROR_NO_MEMORY: isize = -12;
+const ERROR_BAD_ADDRESS: isize = -14;
+const ERROR_NOT_SUPPORTED: isize = -38;
+
+// 用户可以使用的地址上限，更高的地址留给内核
+#[cfg(target_pointer_width = "64")]
+const USER_SPACE_END: usize = 1 << 38;
+#[cfg(target_pointer_width = "32")]
+const USER_SPACE_END: usize = 0xc000_0000;
+
+// 共享内存对象的表，句柄就是表中的下标。对象在关闭句柄之前一直存在，应用程序之间可以通过句柄共享内存
+static SHARED_MEMORY: spin::Mutex<Vec<Option<mm::SharedMemory<&'static mm::DefaultFrameAllocator>>>> = spin::Mutex::new(Vec::new());
+
+// 用户崩溃时，文件名和消息最多复制的长度
+const MAX_PANIC_STRING: usize = 1024;
+
 pub enum SyscallOperation {
     Return(SyscallResult),
     Terminate(i32),
-    UserPanic(Option<&'static str>, u32, u32, Option<&'static str>),
+    UserPanic(Option<String>, u32, u32, Option<String>),
+    // 让出处理核。系统调用暂时不返回，运行时的Future返回Pending，再次运行时才回到用户
+    Yield,
 }
 
 pub struct SyscallResult {
@@ -16,51 +54,258 @@ pub struct SyscallResult {
     pub extra: usize,
 }
 
-pub fn syscall(module: usize, function: usize, args: [usize; 6]) -> SyscallOperation {
+pub fn syscall<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    module: usize, 
+    function: usize, 
+    args: [usize; 6]
+) -> SyscallOperation {
     match module {
-        MODULE_PROCESS => do_process(function, args),
-        MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]]),
-        _ => panic!("Unknown syscall, module: {}, function: {}, args: {:?}", module, function, args),
+        MODULE_PROCESS => do_process(memory_set, function, args),
+        MODULE_TEST_INTERFACE => do_test_interface(memory_set, function, [args[0], args[1], args[2]]),
+        MODULE_MEMORY => do_memory(memory_set, function, [args[0], args[1], args[2]]),
+        _ => {
+            println!("[kernel] Unknown syscall, module: {:#x}, function: {:#x}, args: {:?}", module, function, args);
+            not_supported()
+        },
     }
 }
 
-fn do_process(function: usize, args: [usize; 6]) -> SyscallOperation {
+// 不支持的模块或功能，返回错误码，不结束用户程序
+fn not_supported() -> SyscallOperation {
+    SyscallOperation::Return(SyscallResult { code: ERROR_NOT_SUPPORTED as usize, extra: 0 })
+}
+
+fn do_process<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    function: usize, 
+    args: [usize; 6]
+) -> SyscallOperation {
     match function {
         FUNCTION_PROCESS_EXIT => SyscallOperation::Terminate(args[0] as i32),
+        FUNCTION_PROCESS_YIELD => SyscallOperation::Yield,
         FUNCTION_PROCESS_PANIC => { // [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
             let [line, col, f_buf, f_len, m_buf, m_len] = args;
-            let file_name = if f_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(f_buf as *const u8, f_len) };
-                Some(core::str::from_utf8(slice).unwrap())
-            };
-            let msg = if m_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(m_buf as *const u8, m_len) };
-                Some(core::str::from_utf8(slice).unwrap())
-            };
+            // 用户程序无论如何都要结束，读不出的字符串当作没有提供
+            let file_name = read_user_string(memory_set, f_buf, f_len);
+            let msg = read_user_string(memory_set, m_buf, m_len);
             SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg)
         },
-        _ => panic!("Unknown syscall PROCESS, function: {}, args: {:?}", function, args),
+        _ => {
+            println!("[kernel] Unknown syscall PROCESS, function: {:#x}, args: {:?}", function, args);
+            not_supported()
+        },
     }
 }
 
-fn do_test_interface(function: usize, args: [usize; 3]) -> SyscallOperation {
+fn do_test_interface<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    function: usize, 
+    args: [usize; 3]
+) -> SyscallOperation {
     match function {
         FUNCTION_TEST_WRITE => { // fd: usize, buffer: &[u8] fd, buffer.as_ptr() as usize, buffer.len()
             const STDOUT: usize = 1;
             let [fd, buf, len] = args;
             if fd == STDOUT {
-                let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
-                let str = core::str::from_utf8(slice).unwrap();
-                print!("{}", str);
-                SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize })
-            } else {
-                panic!("Unsupported fd {}", fd);
+                let ans = match write_user_str(memory_set, buf, len) {
+                    Ok(()) => SyscallResult { code: 0, extra: len as usize },
+                    Err(code) => SyscallResult { code: code as usize, extra: 0 },
+                };
+                SyscallOperation::Return(ans)
+            } else { // 只支持标准输出
+                SyscallOperation::Return(SyscallResult { code: ERROR_INVALID_ARGUMENT as usize, extra: 0 })
             }
         },
-        _ => panic!("Unknown syscall TEST_INTERFACE,function: {}, arg: {:?}", function, args),
+        _ => {
+            println!("[kernel] Unknown syscall TEST_INTERFACE, function: {:#x}, args: {:?}", function, args);
+            not_supported()
+        },
+    }
+}
+
+fn do_memory<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    function: usize, 
+    args: [usize; 3]
+) -> SyscallOperation {
+    let ans = match function {
+        FUNCTION_MEMORY_PROTECT => { // addr: usize, len: usize, prot: usize
+            let [addr, len, prot] = args;
+            do_protect(memory_set, addr, len, prot).map(|()| 0)
+        },
+        FUNCTION_MEMORY_SHARE_CREATE => do_share_create::<M>(args[0]), // len: usize
+        FUNCTION_MEMORY_SHARE_MAP => { // handle: usize, addr: usize, prot: usize
+            let [handle, addr, prot] = args;
+            do_share_map(memory_set, handle, addr, prot).map(|()| 0)
+        },
+        FUNCTION_MEMORY_SHARE_UNMAP => do_share_unmap(memory_set, args[0]).map(|()| 0), // addr: usize
+        FUNCTION_MEMORY_SHARE_CLOSE => do_share_close(args[0]).map(|()| 0), // handle: usize
+        _ => {
+            println!("[kernel] Unknown syscall MEMORY, function: {:#x}, args: {:?}", function, args);
+            Err(ERROR_NOT_SUPPORTED)
+        },
+    };
+    let ans = match ans {
+        Ok(extra) => SyscallResult { code: 0, extra },
+        Err(code) => SyscallResult { code: code as usize, extra: 0 },
+    };
+    SyscallOperation::Return(ans)
+}
+
+// 修改用户内存的访问权限。地址需要按页对齐，区间中的每一页都要属于用户的区域
+fn do_protect<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    addr: usize, 
+    len: usize, 
+    prot: usize
+) -> Result<(), isize> {
+    let (vpn, n) = user_pages::<M>(addr, len)?;
+    let flags = prot_flags(prot)?;
+    let vpn_end = VirtAddr(addr + n * (1 << M::FRAME_SIZE_BITS)).page_number::<M>();
+    // 不允许修改内核区域的权限
+    let kernel_area = memory_set.areas()
+        .any(|area| area.range.start < vpn_end && area.range.end > vpn && !area.flags.contains(Sv39Flags::U));
+    if kernel_area {
+        return Err(ERROR_INVALID_ARGUMENT)
+    }
+    // 内核区域已经排除，不会修改借用的全局页表。
+    // 区间中有不属于任何区域的页是参数错误；只有拆分大页时分配不到新的页表才是内存不足
+    match memory_set.protect(vpn, n, flags) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ERROR_INVALID_ARGUMENT),
+        Err(mm::FrameAllocError) => Err(ERROR_NO_MEMORY),
+    }
+}
+
+// 创建len字节的共享内存对象，返回它的句柄
+fn do_share_create<M: PageMode<Flags = Sv39Flags>>(len: usize) -> Result<usize, isize> {
+    let (_, n) = user_pages::<M>(0, len)?;
+    let frame_alloc = crate::FRAME_ALLOC.get().expect("frame allocator initialized");
+    // 页数超过空闲页帧时一定分配不出来，提前返回，避免为页帧数组申请过大的内核堆
+    if n > frame_alloc.lock().stats().free_frames {
+        return Err(ERROR_NO_MEMORY)
+    }
+    let shared = mm::SharedMemory::try_new_in::<M>(n, frame_alloc).map_err(|_| ERROR_NO_MEMORY)?;
+    let mut table = SHARED_MEMORY.lock();
+    match table.iter().position(Option::is_none) {
+        Some(handle) => {
+            table[handle] = Some(shared);
+            Ok(handle)
+        },
+        None => {
+            table.push(Some(shared));
+            Ok(table.len() - 1)
+        },
+    }
+}
+
+// 把句柄对应的共享内存映射到地址addr上，地址需要按页对齐，并且没有被使用
+fn do_share_map<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    handle: usize, 
+    addr: usize, 
+    prot: usize
+) -> Result<(), isize> {
+    let flags = prot_flags(prot)?;
+    let table = SHARED_MEMORY.lock();
+    let shared = table.get(handle).and_then(Option::as_ref).ok_or(ERROR_INVALID_ARGUMENT)?;
+    let page_size = 1 << M::FRAME_SIZE_BITS;
+    let (vpn, n) = user_pages::<M>(addr, shared.page_count() * page_size)?;
+    if !memory_set.is_free(vpn, n) {
+        return Err(ERROR_INVALID_ARGUMENT)
+    }
+    memory_set.map_shared(AreaKind::Mmap, vpn, shared, flags).map_err(|_| ERROR_NO_MEMORY)
+}
+
+// 取消地址addr所在的共享内存区域的映射
+fn do_share_unmap<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    addr: usize
+) -> Result<(), isize> {
+    match memory_set.find_area(VirtAddr(addr)) {
+        Some(area) if area.backing == AreaBacking::Shared => {},
+        _ => return Err(ERROR_INVALID_ARGUMENT),
+    }
+    memory_set.remove_area(VirtAddr(addr)).map(|_| ()).map_err(|_| ERROR_NO_MEMORY)
+}
+
+// 关闭共享内存的句柄。已经建立的映射仍然有效，所有映射都取消以后页帧才被释放
+fn do_share_close(handle: usize) -> Result<(), isize> {
+    match SHARED_MEMORY.lock().get_mut(handle).and_then(Option::take) {
+        Some(_shared) => Ok(()),
+        None => Err(ERROR_INVALID_ARGUMENT),
+    }
+}
+
+// 检查用户的地址区间，得到开始的页号和页数。地址需要按页对齐，区间不能为空，并且在用户的地址空间中
+fn user_pages<M: PageMode>(addr: usize, len: usize) -> Result<(VirtPageNum, usize), isize> {
+    let page_size = 1 << M::FRAME_SIZE_BITS;
+    let n = len / page_size + (len % page_size != 0) as usize;
+    let end = n.checked_mul(page_size).and_then(|size| addr.checked_add(size));
+    match end {
+        Some(end) if addr % page_size == 0 && n > 0 && end <= USER_SPACE_END => Ok((VirtAddr(addr).page_number::<M>(), n)),
+        _ => Err(ERROR_INVALID_ARGUMENT),
+    }
+}
+
+// 把用户的权限位转换为页表项的设置。不支持同时可写和可执行的页，也不支持不能访问的页；RISC-V上可写的页必须可读
+fn prot_flags(prot: usize) -> Result<Sv39Flags, isize> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot & (PROT_WRITE | PROT_EXEC) == PROT_WRITE | PROT_EXEC || prot == 0 {
+        return Err(ERROR_INVALID_ARGUMENT)
+    }
+    let mut flags = Sv39Flags::U;
+    if prot & (PROT_READ | PROT_WRITE) != 0 { flags |= Sv39Flags::R }
+    if prot & PROT_WRITE != 0 { flags |= Sv39Flags::W }
+    if prot & PROT_EXEC != 0 { flags |= Sv39Flags::X }
+    Ok(flags)
+}
+
+// 分段复制用户缓冲区中的字符串并输出；跨过分段边界的字符留到下一段
+fn write_user_str<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    buf: usize, 
+    len: usize
+) -> Result<(), isize> {
+    let mut chunk = [0u8; 64];
+    let (mut done, mut kept) = (0, 0);
+    while done < len {
+        let n = usize::min(chunk.len() - kept, len - done);
+        let src = VirtAddr(buf.wrapping_add(done));
+        memory_set.addr_space_mut().copy_from_user(src, &mut chunk[kept..kept + n]).map_err(user_access_error)?;
+        done += n;
+        let valid = match core::str::from_utf8(&chunk[..kept + n]) {
+            Ok(str) => str.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(), // 末尾的字符还不完整
+            Err(_) => return Err(ERROR_INVALID_ARGUMENT),
+        };
+        print!("{}", core::str::from_utf8(&chunk[..valid]).unwrap());
+        chunk.copy_within(valid..kept + n, 0);
+        kept = kept + n - valid;
+    }
+    if kept != 0 {
+        return Err(ERROR_INVALID_ARGUMENT)
+    }
+    Ok(())
+}
+
+// 复制用户的字符串，过长的部分被截断；地址为0或者不能读出时返回None
+fn read_user_string<M: PageMode<Flags = Sv39Flags>>(
+    memory_set: &mut MemorySet<M, &mm::DefaultFrameAllocator>, 
+    buf: usize, 
+    len: usize
+) -> Option<String> {
+    if buf == 0 {
+        return None
+    }
+    let mut bytes = vec![0u8; usize::min(len, MAX_PANIC_STRING)];
+    memory_set.addr_space_mut().copy_from_user(VirtAddr(buf), &mut bytes).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn user_access_error(err: UserAccessError) -> isize {
+    match err {
+        UserAccessError::Fault(_) => ERROR_BAD_ADDRESS,
+        UserAccessError::OutOfMemory => ERROR_NO_MEMORY,
     }
 }
@@ -0,0 +1,124 @@
+// 内核的异步执行器
+//
+// 每个任务是一个Future，可以是内核自己的异步任务，也可以是包装了用户运行时的任务。
+// 任务返回Pending以后，只有被唤醒才会放回就绪队列；执行器按进入队列的顺序轮流运行就绪的任务。
+// 目前只在一个处理核上运行，唤醒器不需要跨核发送任务
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use core::{
+    cell::Cell,
+    future::Future,
+    mem::ManuallyDrop,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+type ReadyQueue = spin::Mutex<VecDeque<Arc<Task>>>;
+
+pub struct Executor {
+    ready: Arc<ReadyQueue>,
+    // 还没有完成的任务数，包括就绪的和等待唤醒的
+    alive: Cell<usize>,
+}
+
+struct Task {
+    future: spin::Mutex<Option<Pin<Box<dyn Future<Output = ()>>>>>,
+    ready: Arc<ReadyQueue>,
+    // 任务已经在就绪队列中，重复唤醒不需要再次放入
+    queued: AtomicBool,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor { ready: Arc::new(spin::Mutex::new(VecDeque::new())), alive: Cell::new(0) }
+    }
+    // 添加一个任务，放在就绪队列的最后
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        let task = Arc::new(Task {
+            future: spin::Mutex::new(Some(Box::pin(future))),
+            ready: self.ready.clone(),
+            queued: AtomicBool::new(true),
+        });
+        self.alive.set(self.alive.get() + 1);
+        self.ready.lock().push_back(task);
+    }
+    // 运行就绪的任务，直到就绪队列为空；返回还没有完成的任务数。
+    // 返回非零时，剩下的任务都在等待唤醒，需要中断等外部事件才能继续
+    pub fn run_until_idle(&self) -> usize {
+        loop {
+            // 先取出任务再运行，运行时任务可以唤醒自己或者添加新的任务
+            let task = match self.ready.lock().pop_front() {
+                Some(task) => task,
+                None => return self.alive.get(),
+            };
+            task.queued.store(false, Ordering::Release);
+            let waker = waker_of(task.clone());
+            let mut cx = Context::from_waker(&waker);
+            let mut slot = task.future.lock();
+            if let Some(future) = slot.as_mut() {
+                if future.as_mut().poll(&mut cx).is_ready() {
+                    *slot = None; // 完成的任务立即释放，比如用户运行时的地址空间
+                    self.alive.set(self.alive.get() - 1);
+                }
+            }
+        }
+    }
+}
+
+impl Task {
+    fn wake(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.lock().push_back(self.clone());
+        }
+    }
+}
+
+// 唤醒器的数据是任务的Arc指针，克隆时增加引用计数，释放时减少
+fn waker_of(task: Arc<Task>) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(Arc::into_raw(task) as *const (), &VTABLE)) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data as *const Task);
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    let task = Arc::from_raw(data as *const Task);
+    task.wake();
+}
+
+unsafe fn waker_wake_by_ref(data: *const ()) {
+    let task = ManuallyDrop::new(Arc::from_raw(data as *const Task));
+    task.wake();
+}
+
+unsafe fn waker_drop(data: *const ()) {
+    drop(Arc::from_raw(data as *const Task));
+}
+
+// 让出一次处理核：第一次运行时唤醒自己并返回Pending，排到就绪队列的最后
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(())
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,69 @@
+//! 在主机上运行的异步执行器测试
+
+use super::*;
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::RefCell;
+
+// 等待外部唤醒的Future，第一次运行时把唤醒器交给外部
+struct WaitOnce {
+    waker: Rc<RefCell<Option<Waker>>>,
+    polls: Rc<Cell<usize>>,
+}
+
+impl Future for WaitOnce {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.polls.set(self.polls.get() + 1);
+        if self.polls.get() > 1 {
+            return Poll::Ready(())
+        }
+        *self.waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[test]
+fn yield_interleaves_tasks() {
+    let executor = Executor::new();
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    for &name in &['a', 'b'] {
+        let trace = trace.clone();
+        executor.spawn(async move {
+            for i in 0..3 {
+                trace.borrow_mut().push((name, i));
+                yield_now().await;
+            }
+        });
+    }
+    assert_eq!(executor.run_until_idle(), 0, "all tasks finished");
+    let expected = [('a', 0), ('b', 0), ('a', 1), ('b', 1), ('a', 2), ('b', 2)];
+    assert_eq!(trace.borrow().as_slice(), &expected, "tasks run in turn");
+}
+
+#[test]
+fn pending_task_waits_for_wake() {
+    let executor = Executor::new();
+    let (waker, polls) = (Rc::new(RefCell::new(None)), Rc::new(Cell::new(0)));
+    executor.spawn(WaitOnce { waker: waker.clone(), polls: polls.clone() });
+    assert_eq!(executor.run_until_idle(), 1, "task waiting for wake");
+    assert_eq!(executor.run_until_idle(), 1, "not polled again before wake");
+    assert_eq!(polls.get(), 1, "polled once");
+    // 重复唤醒只放入就绪队列一次
+    let waker = waker.borrow_mut().take().expect("waker handed out");
+    waker.wake_by_ref();
+    waker.wake();
+    assert_eq!(executor.run_until_idle(), 0, "task finished after wake");
+    assert_eq!(polls.get(), 2, "polled once more after wake");
+}
+
+#[test]
+fn task_spawns_task() {
+    let executor = Rc::new(Executor::new());
+    let done = Rc::new(Cell::new(false));
+    let (inner_executor, inner_done) = (executor.clone(), done.clone());
+    executor.spawn(async move {
+        inner_executor.spawn(async move { inner_done.set(true) });
+    });
+    assert_eq!(executor.run_until_idle(), 0, "both tasks finished");
+    assert!(done.get(), "spawned task ran");
+}